@@ -0,0 +1,127 @@
+// CRATES
+use hyper::Method;
+use serde_json::{json, Value};
+use std::{net::IpAddr, time::Duration};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Formats the access log can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	Json,
+	Logfmt,
+}
+
+/// Settings of the access log. Client IPs and query strings can identify
+/// users, so they are only logged if explicitly enabled.
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+	pub format: Format,
+	pub client_ip: bool,
+	pub query: bool,
+}
+
+/// An access log entry, started when a request comes in and written once its
+/// response is ready.
+pub struct Entry {
+	format: Format,
+	timestamp: OffsetDateTime,
+	fields: Vec<(&'static str, Value)>,
+}
+
+impl AccessLog {
+	/// Starts an entry for a request. `route` is the pattern of the matching
+	/// route (e.g. `/r/:sub`), so that the log never contains the raw path.
+	pub fn start(&self, method: &Method, route: Option<&str>, client_ip: Option<IpAddr>, query: Option<&str>) -> Entry {
+		let mut fields = vec![("method", json!(method.as_str())), ("route", json!(route))];
+
+		if self.client_ip {
+			fields.push(("client_ip", json!(client_ip.map(|ip| ip.to_string()))));
+		}
+		if self.query {
+			fields.push(("query", json!(query)));
+		}
+
+		Entry {
+			format: self.format,
+			timestamp: OffsetDateTime::now_utc(),
+			fields,
+		}
+	}
+}
+
+impl Entry {
+	/// Completes the entry with the outcome of the request and writes it to
+	/// standard output.
+	pub fn finish(mut self, status: u16, latency: Duration, upstream_calls: u32, upstream_bytes: u64) {
+		self.fields.extend([
+			("status", json!(status)),
+			("latency_ms", json!((latency.as_secs_f64() * 10_000.0).round() / 10.0)),
+			("upstream_calls", json!(upstream_calls)),
+			("upstream_bytes", json!(upstream_bytes)),
+		]);
+
+		println!("{}", self.to_line());
+	}
+
+	fn to_line(&self) -> String {
+		let timestamp = self.timestamp.format(&Rfc3339).unwrap_or_default();
+
+		match self.format {
+			Format::Json => {
+				// Built by hand to keep the fields in order
+				let mut line = format!("{{\"timestamp\":{}", json!(timestamp));
+				for (key, val) in &self.fields {
+					line.push_str(&format!(",\"{}\":{}", key, val));
+				}
+				line.push('}');
+				line
+			}
+			Format::Logfmt => {
+				let mut line = format!("timestamp={}", timestamp);
+				for (key, val) in &self.fields {
+					let val = match val {
+						Value::Null => String::new(),
+						Value::String(s) => logfmt_value(s),
+						other => other.to_string(),
+					};
+					line.push_str(&format!(" {}={}", key, val));
+				}
+				line
+			}
+		}
+	}
+}
+
+/// Quotes a logfmt value if it contains spaces, quotes or equal signs.
+fn logfmt_value(val: &str) -> String {
+	if val.is_empty() || val.contains(|c: char| c.is_whitespace() || c == '"' || c == '=' || c == '\\') {
+		format!("{:?}", val)
+	} else {
+		val.to_string()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_entry_formats() {
+		let log = AccessLog {
+			format: Format::Logfmt,
+			client_ip: false,
+			query: true,
+		};
+		let mut entry = log.start(&Method::GET, Some("/r/:sub"), "127.0.0.1".parse().ok(), Some("q=a b"));
+		entry.timestamp = OffsetDateTime::UNIX_EPOCH;
+		entry.fields.push(("status", json!(200)));
+
+		assert_eq!(entry.to_line(), r#"timestamp=1970-01-01T00:00:00Z method=GET route=/r/:sub query="q=a b" status=200"#);
+
+		entry.format = Format::Json;
+		assert_eq!(
+			entry.to_line(),
+			r#"{"timestamp":"1970-01-01T00:00:00Z","method":"GET","route":"/r/:sub","query":"q=a b","status":200}"#
+		);
+	}
+}
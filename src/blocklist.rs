@@ -0,0 +1,110 @@
+// CRATES
+use crate::{
+	config::get_setting,
+	utils::{error_with_status, Post},
+};
+use hyper::{Body, Request, Response};
+
+// Shown on the 451 page unless LIBREDDIT_BLOCKED_MESSAGE is set
+const DEFAULT_MESSAGE: &str = "This content is not available on this instance.";
+
+/// Whether `pattern` is a valid blocklist entry: a subreddit or user name,
+/// optionally containing `*` and `?` wildcards.
+pub fn is_pattern(pattern: &str) -> bool {
+	pattern.chars().all(|c| c.is_ascii_alphanumeric() || "_-*?".contains(c))
+}
+
+/// Whether the subreddit is blocked through LIBREDDIT_BLOCKED_SUBREDDITS.
+pub fn subreddit_blocked(name: &str) -> bool {
+	listed("LIBREDDIT_BLOCKED_SUBREDDITS", name)
+}
+
+/// Whether the user is blocked through LIBREDDIT_BLOCKED_USERS.
+pub fn user_blocked(name: &str) -> bool {
+	listed("LIBREDDIT_BLOCKED_USERS", name)
+}
+
+/// Whether the post was made in a blocked subreddit or by a blocked user.
+pub fn post_blocked(post: &Post) -> bool {
+	subreddit_blocked(&post.community) || user_blocked(&post.author.name)
+}
+
+/// Removes the blocked subreddits from a `+`-separated list of subreddits.
+/// Returns `None` if all of them are blocked.
+pub fn allowed_subreddits(subs: &str) -> Option<String> {
+	let allowed: Vec<&str> = subs.split('+').filter(|sub| !subreddit_blocked(sub)).collect();
+	(!allowed.is_empty()).then(|| allowed.join("+"))
+}
+
+/// Renders the page shown in place of blocked subreddits, users and posts,
+/// with the message set through LIBREDDIT_BLOCKED_MESSAGE.
+pub async fn unavailable(req: Request<Body>) -> Result<Response<Body>, String> {
+	let msg = get_setting("LIBREDDIT_BLOCKED_MESSAGE")
+		.filter(|msg| !msg.is_empty())
+		.unwrap_or_else(|| DEFAULT_MESSAGE.to_string());
+	error_with_status(req, 451, msg).await
+}
+
+fn listed(setting: &str, name: &str) -> bool {
+	!name.is_empty() && get_setting(setting).is_some_and(|patterns| patterns.split(',').any(|pattern| glob_match(pattern.trim(), name)))
+}
+
+/// Matches a name against a pattern where `*` stands for any number of
+/// characters and `?` for exactly one. Reddit names are case-insensitive, so
+/// the match is as well.
+fn glob_match(pattern: &str, name: &str) -> bool {
+	let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+	let name: Vec<char> = name.to_ascii_lowercase().chars().collect();
+
+	// Position of the last `*` in the pattern and of the name character it
+	// currently stands in for, to backtrack to when the rest fails to match
+	let (mut p, mut n) = (0, 0);
+	let mut star: Option<(usize, usize)> = None;
+
+	while n < name.len() {
+		match pattern.get(p) {
+			Some('*') => {
+				star = Some((p, n));
+				p += 1;
+			}
+			Some(c) if *c == '?' || *c == name[n] => {
+				p += 1;
+				n += 1;
+			}
+			_ => match star {
+				Some((star_p, star_n)) => {
+					star = Some((star_p, star_n + 1));
+					p = star_p + 1;
+					n = star_n + 1;
+				}
+				None => return false,
+			},
+		}
+	}
+
+	pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_glob_match() {
+		assert!(glob_match("news", "News"));
+		assert!(!glob_match("news", "worldnews"));
+		assert!(glob_match("*news", "worldnews"));
+		assert!(glob_match("crypto*", "CryptoCurrency"));
+		assert!(glob_match("*coin*", "bitcoinmarkets"));
+		assert!(glob_match("a?c", "abc"));
+		assert!(!glob_match("a?c", "ac"));
+		assert!(glob_match("*", "anything"));
+		assert!(!glob_match("", "anything"));
+	}
+
+	#[test]
+	fn test_is_pattern() {
+		assert!(is_pattern("Crypto_*"));
+		assert!(!is_pattern("r/news"));
+	}
+}
@@ -0,0 +1,283 @@
+// CRATES
+use crate::utils::{error, is_post_id, local_path, param, redirect, setting, template, time, Post, Preferences};
+use crate::{profile, settings};
+use askama::Template;
+use hyper::{Body, Request, Response};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+use std::fmt;
+use time::OffsetDateTime;
+
+/// Separates the bookmarks in the `bookmarks` cookie.
+pub const SEPARATOR: char = '|';
+
+/// Bookmarks are kept in a cookie, so only the latest this many are kept.
+pub const MAX_BOOKMARKS: usize = 50;
+
+// Titles are cut to this many characters to keep the cookie small
+const MAX_TITLE_LENGTH: usize = 80;
+
+// Characters escaped in titles: the separators, and those not allowed in
+// cookie values
+const TITLE_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'%').add(b',').add(b':').add(b';').add(b'\\').add(b'|');
+
+/// A saved post or comment.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Bookmark {
+	pub sub: String,
+	/// ID of the post, or of the post the comment was made on.
+	pub id: String,
+	/// ID of the comment, empty for posts.
+	pub comment: String,
+	pub title: String,
+	/// Unix timestamp of when it was saved.
+	pub saved: i64,
+}
+
+impl Bookmark {
+	/// Creates a bookmark for the post or comment at `permalink`, a path like
+	/// `/r/{sub}/comments/{id}/{title}/{comment}`.
+	pub fn new(permalink: &str, title: &str) -> Option<Self> {
+		let parts: Vec<&str> = permalink.split('/').filter(|part| !part.is_empty()).collect();
+		let bookmark = match parts.as_slice() {
+			["r", sub, "comments", id, rest @ ..] if rest.len() <= 2 => Self {
+				sub: sub.to_string(),
+				id: id.to_string(),
+				comment: rest.get(1).map(ToString::to_string).unwrap_or_default(),
+				title: title.trim().chars().take(MAX_TITLE_LENGTH).collect(),
+				saved: OffsetDateTime::now_utc().unix_timestamp(),
+			},
+			_ => return None,
+		};
+		bookmark.check().then_some(bookmark)
+	}
+
+	/// Parses a bookmark from the form it is kept in within the `bookmarks`
+	/// cookie, `sub:id:comment:saved:title` with the title percent-encoded.
+	pub fn parse(bookmark: &str) -> Option<Self> {
+		let mut parts = bookmark.splitn(5, ':');
+		let bookmark = Self {
+			sub: parts.next()?.to_string(),
+			id: parts.next()?.to_string(),
+			comment: parts.next()?.to_string(),
+			saved: parts.next()?.parse().ok()?,
+			title: percent_decode_str(parts.next()?).decode_utf8().ok()?.into_owned(),
+		};
+		bookmark.check().then_some(bookmark)
+	}
+
+	/// Whether `bookmark` is a valid bookmark in its cookie form.
+	pub fn is_valid(bookmark: &str) -> bool {
+		Self::parse(bookmark).is_some()
+	}
+
+	fn check(&self) -> bool {
+		!self.sub.is_empty() && self.sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && is_post_id(&self.id) && (self.comment.is_empty() || is_post_id(&self.comment))
+	}
+
+	/// Reads the bookmarks from the `bookmarks` cookie, oldest first.
+	pub fn all(req: &Request<Body>) -> Vec<Self> {
+		setting(req, "bookmarks").split(SEPARATOR).filter_map(Self::parse).collect()
+	}
+
+	/// Whether this bookmark is for the same post or comment as `other`.
+	fn same_as(&self, other: &Self) -> bool {
+		self.id == other.id && self.comment == other.comment
+	}
+
+	/// Path of the post or comment on this instance, and on Reddit.
+	pub fn permalink(&self) -> String {
+		match self.comment.as_str() {
+			"" => format!("/r/{}/comments/{}/", self.sub, self.id),
+			comment => format!("/r/{}/comments/{}/_/{}/", self.sub, self.id, comment),
+		}
+	}
+
+	// Name Reddit's API knows the post or comment by
+	fn fullname(&self) -> String {
+		match self.comment.as_str() {
+			"" => format!("t3_{}", self.id),
+			comment => format!("t1_{}", comment),
+		}
+	}
+}
+
+impl fmt::Display for Bookmark {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}:{}:{}:{}:{}",
+			self.sub,
+			self.id,
+			self.comment,
+			self.saved,
+			utf8_percent_encode(&self.title, TITLE_ESCAPES)
+		)
+	}
+}
+
+// A bookmark with the post or comment it points to, if Reddit still has it
+struct Saved {
+	bookmark: Bookmark,
+	post: Option<Post>,
+	rel_time: String,
+	created: String,
+}
+
+#[derive(Template)]
+#[template(path = "saved.html")]
+struct SavedTemplate {
+	saved: Vec<Saved>,
+	prefs: Preferences,
+	url: String,
+}
+
+// SERVICES
+
+// Show the saved posts and comments, latest first
+pub async fn page(req: Request<Body>) -> Result<Response<Body>, String> {
+	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
+	let bookmarks: Vec<Bookmark> = Bookmark::all(&req).into_iter().rev().collect();
+
+	// Fetch every saved post and comment at once
+	let mut posts = Vec::new();
+	if !bookmarks.is_empty() {
+		let ids: Vec<String> = bookmarks.iter().map(Bookmark::fullname).collect();
+		match Post::fetch(&format!("/api/info.json?id={}&raw_json=1", ids.join(",")), false).await {
+			Ok((fetched, _)) => posts = fetched,
+			Err(msg) => return error(req, msg).await,
+		}
+	}
+
+	let saved = bookmarks
+		.into_iter()
+		.map(|bookmark| {
+			let id = if bookmark.comment.is_empty() { &bookmark.id } else { &bookmark.comment };
+			let post = posts.iter().position(|post| post.id == *id).map(|i| posts.swap_remove(i));
+			let (rel_time, created) = time(bookmark.saved as f64);
+			Saved {
+				bookmark,
+				post,
+				rel_time,
+				created,
+			}
+		})
+		.collect();
+
+	template(SavedTemplate {
+		saved,
+		prefs: Preferences::new(&req),
+		url,
+	})
+}
+
+// Save a post or comment, or remove it from the bookmarks
+pub async fn save(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+	let unsave = req.uri().path().starts_with("/unsave");
+	let mut bookmarks = Bookmark::all(&req);
+
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+
+	let Some(bookmark) = Bookmark::new(
+		form.get("permalink").map(String::as_str).unwrap_or_default(),
+		form.get("title").map(String::as_str).unwrap_or_default(),
+	) else {
+		return Err("Only posts and comments can be saved".to_string());
+	};
+	bookmarks.retain(|saved| !saved.same_as(&bookmark));
+	if !unsave {
+		bookmarks.push(bookmark);
+		let excess = bookmarks.len().saturating_sub(MAX_BOOKMARKS);
+		bookmarks.drain(..excess);
+	}
+
+	let value = (!bookmarks.is_empty()).then(|| bookmarks.iter().map(ToString::to_string).collect::<Vec<_>>().join(&SEPARATOR.to_string()));
+	let mut response = redirect(local_path(form.get("redirect").cloned()));
+	settings::save(token.as_deref(), &mut response, &[("bookmarks".to_string(), value)])?;
+	Ok(response)
+}
+
+// Download the bookmarks as JSON or as a Markdown list
+pub async fn export(req: Request<Body>) -> Result<Response<Body>, String> {
+	let bookmarks: Vec<Bookmark> = Bookmark::all(&req).into_iter().rev().collect();
+
+	let (body, content_type, extension) = match param(&req.uri().to_string(), "format").as_deref() {
+		Some("markdown") => (markdown(&bookmarks), "text/markdown; charset=utf-8", "md"),
+		_ => (json(&bookmarks)?, "application/json", "json"),
+	};
+
+	Response::builder()
+		.status(200)
+		.header("content-type", content_type)
+		.header("content-disposition", format!("attachment; filename=\"libreddit-saved.{}\"", extension))
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+fn json(bookmarks: &[Bookmark]) -> Result<String, String> {
+	let bookmarks: Vec<serde_json::Value> = bookmarks
+		.iter()
+		.map(|bookmark| {
+			serde_json::json!({
+				"title": bookmark.title,
+				"subreddit": bookmark.sub,
+				"kind": if bookmark.comment.is_empty() { "post" } else { "comment" },
+				"url": format!("https://www.reddit.com{}", bookmark.permalink()),
+				"saved": bookmark.saved,
+			})
+		})
+		.collect();
+	serde_json::to_string_pretty(&bookmarks).map_err(|e| e.to_string())
+}
+
+fn markdown(bookmarks: &[Bookmark]) -> String {
+	let mut markdown = String::from("# Saved posts and comments\n\n");
+	for bookmark in bookmarks {
+		let title = if bookmark.title.is_empty() { bookmark.permalink() } else { bookmark.title.clone() };
+		let title = title.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]");
+		let saved = OffsetDateTime::from_unix_timestamp(bookmark.saved).map(|date| date.date().to_string()).unwrap_or_default();
+		markdown.push_str(&format!(
+			"- [{}](https://www.reddit.com{}) (r/{}, saved {})\n",
+			title,
+			bookmark.permalink(),
+			bookmark.sub,
+			saved
+		));
+	}
+	markdown
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bookmark() {
+		let bookmark = Bookmark::new("/r/rust/comments/abc123/some_title/def456/", "Why: \"Rust\" | 100%").unwrap();
+		assert_eq!(bookmark.comment, "def456");
+		assert_eq!(bookmark.permalink(), "/r/rust/comments/abc123/_/def456/");
+		assert_eq!(Bookmark::parse(&bookmark.to_string()), Some(bookmark.clone()));
+		assert!(!bookmark.to_string()[bookmark.to_string().find("Why").unwrap()..].contains([':', '|', ' ']));
+
+		assert_eq!(Bookmark::new("/r/rust/comments/abc123/", "").unwrap().fullname(), "t3_abc123");
+		assert!(Bookmark::new("/r/rust/wiki/index", "").is_none());
+		assert!(Bookmark::new("/r/rust/comments/ABC/", "").is_none());
+	}
+
+	#[test]
+	fn test_markdown() {
+		let bookmark = Bookmark {
+			sub: "rust".to_string(),
+			id: "abc123".to_string(),
+			comment: String::new(),
+			title: "[Media] Ferris".to_string(),
+			saved: 0,
+		};
+		assert_eq!(
+			markdown(&[bookmark]),
+			"# Saved posts and comments\n\n- [\\[Media\\] Ferris](https://www.reddit.com/r/rust/comments/abc123/) (r/rust, saved 1970-01-01)\n"
+		);
+	}
+}
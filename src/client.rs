@@ -1,19 +1,38 @@
 use cached::proc_macro::cached;
-use futures_lite::{future::Boxed, FutureExt};
+use futures_lite::{future::Boxed, stream, FutureExt};
 use hyper::client::HttpConnector;
-use hyper::{body, body::Buf, client, header, Body, Client, Method, Request, Response, Uri};
+use hyper::{body, body::Buf, body::HttpBody, client, header, Body, Client, Method, Request, Response, StatusCode, Uri};
 use hyper_rustls::HttpsConnector;
 use libflate::gzip;
 use once_cell::sync::Lazy;
 use percent_encoding::{percent_encode, CONTROLS};
 use serde_json::Value;
-use std::{io, result::Result};
-
-use crate::dbg_msg;
-use crate::server::RequestExt;
+use std::{
+	collections::hash_map::DefaultHasher,
+	hash::{Hash, Hasher},
+	io,
+	result::Result,
+	time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::server::{self, RequestExt};
+use crate::{dbg_msg, health};
 
 const REDDIT_URL_BASE: &str = "https://www.reddit.com";
 
+// How long a request may wait for a free upstream slot before giving up
+const UPSTREAM_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Caps the number of concurrent requests to Reddit, if configured through
+// LIBREDDIT_MAX_UPSTREAM_REQUESTS.
+static UPSTREAM_SLOTS: Lazy<Option<Semaphore>> = Lazy::new(|| {
+	crate::config::get_setting("LIBREDDIT_MAX_UPSTREAM_REQUESTS")
+		.and_then(|max| max.parse::<usize>().ok())
+		.filter(|max| *max > 0)
+		.map(Semaphore::new)
+});
+
 static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
 	let https = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots().https_only().enable_http1().build();
 	client::Client::builder().build(https)
@@ -33,7 +52,10 @@ static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
 /// 429, or if we were unable to decode the value in the `Location` header.
 #[cached(size = 1024, time = 600, result = true)]
 pub async fn canonical_path(path: String) -> Result<Option<String>, String> {
-	let res = reddit_head(path.clone(), true).await?;
+	let _slot = upstream_slot().await?;
+	let res = reddit_head(path.clone(), true).await.inspect_err(|e| health::note_upstream_failure(e))?;
+	server::note_upstream_call(0);
+	note_upstream_status(res.status());
 
 	if res.status() == 429 {
 		return Err("Too many requests.".to_string());
@@ -92,6 +114,9 @@ async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String
 		.request(stream_request)
 		.await
 		.map(|mut res| {
+			let length = res.headers().get(header::CONTENT_LENGTH).and_then(|hdr| hdr.to_str().ok()?.parse().ok());
+			server::note_upstream_call(length.unwrap_or_default());
+
 			let mut rm = |key: &str| res.headers_mut().remove(key);
 
 			rm("access-control-expose-headers");
@@ -107,11 +132,46 @@ async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String
 			rm("Nel");
 			rm("Report-To");
 
-			res
+			res.map(until_shutdown)
 		})
 		.map_err(|e| e.to_string())
 }
 
+/// Passes `body` through until the server starts shutting down, then aborts
+/// it. Long media streams would otherwise hold up draining until the
+/// shutdown timeout.
+fn until_shutdown(body: Body) -> Body {
+	Body::wrap_stream(stream::unfold(Some(body), |body| async move {
+		let mut body = body?;
+		let chunk = async { body.data().await.map(|chunk| chunk.map_err(io::Error::other)) }
+			.or(async {
+				server::shutdown_started().await;
+				Some(Err(io::Error::new(io::ErrorKind::Interrupted, "server is shutting down")))
+			})
+			.await?;
+
+		// Stop after the first error
+		let next = chunk.is_ok().then_some(body);
+		Some((chunk, next))
+	}))
+}
+
+/// Waits for a free slot to make a request to Reddit. Gives up after a while,
+/// so that a flood of requests doesn't pile up while Reddit is slow.
+async fn upstream_slot() -> Result<Option<SemaphorePermit<'static>>, String> {
+	let Some(slots) = UPSTREAM_SLOTS.as_ref() else {
+		return Ok(None);
+	};
+
+	match tokio::time::timeout(UPSTREAM_QUEUE_TIMEOUT, slots.acquire()).await {
+		Ok(Ok(permit)) => Ok(Some(permit)),
+		_ => {
+			server::note_throttled();
+			Err("Too many requests".to_string())
+		}
+	}
+}
+
 /// Makes a GET request to Reddit at `path`. By default, this will honor HTTP
 /// 3xx codes Reddit returns and will automatically redirect.
 fn reddit_get(path: String, quarantine: bool) -> Boxed<Result<Response<Body>, String>> {
@@ -253,24 +313,41 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 }
 
 // Make a request to a Reddit API and parse the JSON response
-#[cached(size = 100, time = 30, result = true)]
 pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
+	let (json, digest) = fetch_json(path, quarantine).await?;
+
+	// Let the server know which payload this response was built from, so it
+	// can answer conditional requests for the rendered page.
+	crate::server::note_upstream(digest);
+
+	Ok(json)
+}
+
+// Fetch and parse the JSON response, along with a digest of the raw payload
+// that identifies it.
+#[cached(size = 100, time = 30, result = true)]
+async fn fetch_json(path: String, quarantine: bool) -> Result<(Value, u64), String> {
 	// Closure to quickly build errors
-	let err = |msg: &str, e: String| -> Result<Value, String> {
+	let err = |msg: &str, e: String| -> Result<(Value, u64), String> {
 		// eprintln!("{} - {}: {}", url, msg, e);
 		Err(format!("{}: {}", msg, e))
 	};
 
+	let _slot = upstream_slot().await?;
+
 	// Fetch the url...
 	match reddit_get(path.clone(), quarantine).await {
 		Ok(response) => {
 			let status = response.status();
+			note_upstream_status(status);
 
 			// asynchronously aggregate the chunks of the body
-			match hyper::body::aggregate(response).await {
+			match hyper::body::to_bytes(response).await {
 				Ok(body) => {
+					server::note_upstream_call(body.len() as u64);
+
 					// Parse the response from Reddit as JSON
-					match serde_json::from_reader(body.reader()) {
+					match serde_json::from_slice(&body) {
 						Ok(value) => {
 							let json: Value = value;
 							// If Reddit returned an error
@@ -287,7 +364,9 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 										.to_string(),
 								)
 							} else {
-								Ok(json)
+								let mut hasher = DefaultHasher::new();
+								body.hash(&mut hasher);
+								Ok((json, hasher.finish()))
 							}
 						}
 						Err(e) => {
@@ -302,6 +381,18 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 				Err(e) => err("Failed receiving body from Reddit", e.to_string()),
 			}
 		}
-		Err(e) => err("Couldn't send request to Reddit", e),
+		Err(e) => {
+			health::note_upstream_failure(&e);
+			err("Couldn't send request to Reddit", e)
+		}
+	}
+}
+
+/// Tells the readiness check whether Reddit answered a request properly.
+fn note_upstream_status(status: StatusCode) {
+	if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+		health::note_upstream_failure(&format!("Reddit responded with {}", status));
+	} else {
+		health::note_upstream_success();
 	}
 }
@@ -1,25 +1,64 @@
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::{env::var, fs::read_to_string};
+use std::{
+	collections::HashMap,
+	env::{var, var_os, vars_os},
+	fmt,
+	fs::{self, read_to_string},
+	path::{Path, PathBuf},
+	sync::{Arc, OnceLock, RwLock},
+	time::{Duration, SystemTime},
+};
+use time::OffsetDateTime;
 
 // Waiting for https://github.com/rust-lang/rust/issues/74465 to land, so we
 // can reduce reliance on once_cell.
 //
 // This is the local static that is initialized at runtime (technically at
-// first request) and contains the instance settings.
-pub(crate) static CONFIG: Lazy<Config> = Lazy::new(Config::load);
+// first request) and contains the instance settings. It is swapped out as a
+// whole when the configuration is reloaded, so readers holding on to a
+// snapshot never see a mix of old and new settings.
+pub(crate) static CONFIG: Lazy<RwLock<Arc<Config>>> = Lazy::new(|| RwLock::new(Arc::new(Config::load())));
+
+// The config file given on the command line, if any
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// The HSTS max-age given on the command line, if any
+static HSTS_MAX_AGE: OnceLock<String> = OnceLock::new();
+
+// When the configuration was last reloaded, if ever
+static RELOADED_AT: RwLock<Option<OffsetDateTime>> = RwLock::new(None);
+
+// How often the config file is checked for changes when watching it
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
 
 // This serves as the frontend for the Pushshift API - on removed comments, this URL will
 // be the base of a link, to display removed content (on another site).
 pub(crate) const DEFAULT_PUSHSHIFT_FRONTEND: &str = "www.unddit.com";
 
+// Security headers sent with every response unless overridden. `{nonce}` is
+// replaced with a random value generated for each request, which templates
+// put on their <style> elements.
+pub(crate) const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; font-src 'self'; script-src 'self' blob:; manifest-src 'self'; media-src 'self' data: blob: about:; style-src 'self' 'nonce-{nonce}'; base-uri 'none'; img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; connect-src 'self'; worker-src blob:;";
+pub(crate) const DEFAULT_X_FRAME_OPTIONS: &str = "DENY";
+pub(crate) const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+const DEFAULT_HSTS_MAX_AGE: &str = "604800";
+
 /// Stores the configuration parsed from the environment variables and the
 /// config file. `Config::Default()` contains None for each setting.
-/// When adding more config settings, add it to `Config::load`,
-/// `get_setting_from_config`, both below, as well as
+/// When adding more config settings, add it to `Config::load_from`,
+/// `SETTINGS` and `get_setting_from_config`, all below, as well as
 /// instance_info::InstanceInfo.to_string(), README.md and app.json.
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
+	// Everything found wrong with the config file and environment variables
+	#[serde(skip)]
+	pub(crate) problems: Vec<Problem>,
+
+	// Whether the config file itself could not be read or had invalid values
+	#[serde(skip)]
+	pub(crate) file_errors: bool,
+
 	#[serde(rename = "LIBREDDIT_SFW_ONLY")]
 	pub(crate) sfw_only: Option<String>,
 
@@ -62,6 +101,21 @@ pub struct Config {
 	#[serde(rename = "LIBREDDIT_DEFAULT_DISABLE_VISIT_REDDIT_CONFIRMATION")]
 	pub(crate) default_disable_visit_reddit_confirmation: Option<String>,
 
+	#[serde(rename = "LIBREDDIT_DEFAULT_MIN_SCORE")]
+	pub(crate) default_min_score: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_DEFAULT_MIN_RATIO")]
+	pub(crate) default_min_ratio: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_DEFAULT_POST_TYPES")]
+	pub(crate) default_post_types: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_DEFAULT_HIDE_STICKIED")]
+	pub(crate) default_hide_stickied: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_DEFAULT_TRACK_READ")]
+	pub(crate) default_track_read: Option<String>,
+
 	#[serde(rename = "LIBREDDIT_BANNER")]
 	pub(crate) banner: Option<String>,
 
@@ -70,22 +124,167 @@ pub struct Config {
 
 	#[serde(rename = "LIBREDDIT_PUSHSHIFT_FRONTEND")]
 	pub(crate) pushshift: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_RATE_LIMIT")]
+	pub(crate) rate_limit: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_RATE_LIMIT_BURST")]
+	pub(crate) rate_limit_burst: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_RATE_LIMIT_IPV6_PREFIX")]
+	pub(crate) rate_limit_ipv6_prefix: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_TRUSTED_PROXIES")]
+	pub(crate) trusted_proxies: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_MAX_UPSTREAM_REQUESTS")]
+	pub(crate) max_upstream_requests: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_BASE_PATH")]
+	pub(crate) base_path: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_CONTENT_SECURITY_POLICY")]
+	pub(crate) content_security_policy: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_X_FRAME_OPTIONS")]
+	pub(crate) x_frame_options: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_REFERRER_POLICY")]
+	pub(crate) referrer_policy: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_STRICT_TRANSPORT_SECURITY")]
+	pub(crate) strict_transport_security: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_EXTRA_HEADERS")]
+	pub(crate) extra_headers: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_BLOCKED_SUBREDDITS")]
+	pub(crate) blocked_subreddits: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_BLOCKED_USERS")]
+	pub(crate) blocked_users: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_BLOCKED_MESSAGE")]
+	pub(crate) blocked_message: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_CONTENT_POLICY")]
+	pub(crate) content_policy: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_PROFILES")]
+	pub(crate) profiles: Option<String>,
+
+	#[serde(rename = "LIBREDDIT_PROFILES_PATH", skip_serializing_if = "Option::is_none")]
+	pub(crate) profiles_path: Option<String>,
 }
 
 impl Config {
-	/// Load the configuration from the environment variables and the config file.
+	/// Load the configuration from the environment variables and the config
+	/// file, and report any problems with it. Invalid values are left unset.
 	/// In the case that there are no environment variables set and there is no
 	/// config file, this function returns a Config that contains all None values.
 	pub fn load() -> Self {
-		// Read from libreddit.toml config file. If for any reason, it fails, the
-		// default `Config` is used (all None values)
-		let config: Config = toml::from_str(&read_to_string("libreddit.toml").unwrap_or_default()).unwrap_or_default();
+		let config = Self::load_from(path().as_deref());
+		for problem in &config.problems {
+			eprintln!("{}", problem);
+		}
+		config
+	}
+
+	/// Load the configuration from the environment variables and the config
+	/// file at `path`, if any.
+	pub fn load_from(path: Option<&Path>) -> Self {
+		let mut problems = Vec::new();
+		let mut file_errors = false;
+
+		// Read the config file. Without one, all settings come from the
+		// environment; if it is broken, none of its settings are used.
+		let mut file = HashMap::new();
+		if let Some(path) = path {
+			let table = match read_to_string(path).map(|file| toml::from_str::<toml::Table>(&file)) {
+				Ok(Ok(table)) => table,
+				Ok(Err(e)) => {
+					problems.push(Problem::Error(format!("Failed to parse {}: {}", path.display(), e)));
+					file_errors = true;
+					toml::Table::new()
+				}
+				Err(e) => {
+					problems.push(Problem::Error(format!("Failed to read {}: {}", path.display(), e)));
+					file_errors = true;
+					toml::Table::new()
+				}
+			};
+
+			for (key, value) in table {
+				match kind(&key) {
+					Some(kind) => match kind.parse(&value) {
+						Ok(value) => {
+							file.insert(key, value);
+						}
+						Err(e) => {
+							problems.push(Problem::Error(format!("{} in {}: {}", key, path.display(), e)));
+							file_errors = true;
+						}
+					},
+					None => problems.push(Problem::Warning(format!("Unknown setting {} in {}", key, path.display()))),
+				}
+			}
+		}
+
+		let mut env = HashMap::new();
+		for (key, value) in vars_os().filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?))) {
+			if !key.starts_with("LIBREDDIT_") || key == "LIBREDDIT_CONFIG" {
+				continue;
+			}
+
+			// Following the Docker secrets convention, LIBREDDIT_X_FILE names a
+			// file to read the value of LIBREDDIT_X from
+			let (key, value) = match key.strip_suffix("_FILE").filter(|setting| kind(setting).is_some()) {
+				Some(setting) if var(setting).is_ok() => {
+					problems.push(Problem::Error(format!("Both {} and {} are set, ignoring {}", setting, key, key)));
+					continue;
+				}
+				Some(setting) => match read_to_string(&value) {
+					Ok(contents) => (setting.to_string(), contents.trim_end_matches(['\r', '\n']).to_string()),
+					Err(e) => {
+						problems.push(Problem::Error(format!("Environment variable {}: failed to read {}: {}", key, value, e)));
+						continue;
+					}
+				},
+				None => (key, value),
+			};
+
+			// Older versions read this setting from the wrong variable
+			let key = if key == "LIBREDDIT_DEFAULT_HIDE_HLS" {
+				problems.push(Problem::Warning(
+					"LIBREDDIT_DEFAULT_HIDE_HLS is deprecated, use LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION".to_string(),
+				));
+				if var("LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION").is_ok() {
+					continue;
+				}
+				"LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION".to_string()
+			} else {
+				key
+			};
+
+			match kind(&key) {
+				Some(kind) => match kind.parse(&toml::Value::String(value)) {
+					Ok(value) => {
+						env.insert(key, value);
+					}
+					Err(e) => problems.push(Problem::Error(format!("Environment variable {}: {}", key, e))),
+				},
+				None => problems.push(Problem::Warning(format!("Unknown environment variable {}", key))),
+			}
+		}
+
 		// This function defines the order of preference - first check for
 		// environment variables with "LIBREDDIT", then check the config, then if
-		// both are `None`, return a `None` via the `map_or_else` function
-		let parse = |key: &str| -> Option<String> { var(key).ok().map_or_else(|| get_setting_from_config(key, &config), Some) };
+		// both are `None`, return a `None`
+		let parse = |key: &str| -> Option<String> { env.get(key).or_else(|| file.get(key)).cloned() };
 
 		Self {
+			problems,
+			file_errors,
 			sfw_only: parse("LIBREDDIT_SFW_ONLY"),
 			default_theme: parse("LIBREDDIT_DEFAULT_THEME"),
 			default_front_page: parse("LIBREDDIT_DEFAULT_FRONT_PAGE"),
@@ -96,17 +295,197 @@ impl Config {
 			default_show_nsfw: parse("LIBREDDIT_DEFAULT_SHOW_NSFW"),
 			default_blur_nsfw: parse("LIBREDDIT_DEFAULT_BLUR_NSFW"),
 			default_use_hls: parse("LIBREDDIT_DEFAULT_USE_HLS"),
-			default_hide_hls_notification: parse("LIBREDDIT_DEFAULT_HIDE_HLS"),
+			default_hide_hls_notification: parse("LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION"),
 			default_hide_awards: parse("LIBREDDIT_DEFAULT_HIDE_AWARDS"),
 			default_subscriptions: parse("LIBREDDIT_DEFAULT_SUBSCRIPTIONS"),
 			default_disable_visit_reddit_confirmation: parse("LIBREDDIT_DEFAULT_DISABLE_VISIT_REDDIT_CONFIRMATION"),
+			default_min_score: parse("LIBREDDIT_DEFAULT_MIN_SCORE"),
+			default_min_ratio: parse("LIBREDDIT_DEFAULT_MIN_RATIO"),
+			default_post_types: parse("LIBREDDIT_DEFAULT_POST_TYPES"),
+			default_hide_stickied: parse("LIBREDDIT_DEFAULT_HIDE_STICKIED"),
+			default_track_read: parse("LIBREDDIT_DEFAULT_TRACK_READ"),
 			banner: parse("LIBREDDIT_BANNER"),
 			robots_disable_indexing: parse("LIBREDDIT_ROBOTS_DISABLE_INDEXING"),
 			pushshift: parse("LIBREDDIT_PUSHSHIFT_FRONTEND"),
+			rate_limit: parse("LIBREDDIT_RATE_LIMIT"),
+			rate_limit_burst: parse("LIBREDDIT_RATE_LIMIT_BURST"),
+			rate_limit_ipv6_prefix: parse("LIBREDDIT_RATE_LIMIT_IPV6_PREFIX"),
+			trusted_proxies: parse("LIBREDDIT_TRUSTED_PROXIES"),
+			max_upstream_requests: parse("LIBREDDIT_MAX_UPSTREAM_REQUESTS"),
+			base_path: parse("LIBREDDIT_BASE_PATH"),
+			content_security_policy: parse("LIBREDDIT_CONTENT_SECURITY_POLICY"),
+			x_frame_options: parse("LIBREDDIT_X_FRAME_OPTIONS"),
+			referrer_policy: parse("LIBREDDIT_REFERRER_POLICY"),
+			strict_transport_security: parse("LIBREDDIT_STRICT_TRANSPORT_SECURITY"),
+			extra_headers: parse("LIBREDDIT_EXTRA_HEADERS"),
+			blocked_subreddits: parse("LIBREDDIT_BLOCKED_SUBREDDITS"),
+			blocked_users: parse("LIBREDDIT_BLOCKED_USERS"),
+			blocked_message: parse("LIBREDDIT_BLOCKED_MESSAGE"),
+			content_policy: parse("LIBREDDIT_CONTENT_POLICY"),
+			profiles: parse("LIBREDDIT_PROFILES"),
+			profiles_path: parse("LIBREDDIT_PROFILES_PATH"),
+		}
+	}
+}
+
+/// Something wrong with the configuration. Errors make the offending setting
+/// be ignored; warnings point out things that are likely mistakes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Problem {
+	Warning(String),
+	Error(String),
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Warning(msg) => write!(f, "warning: {}", msg),
+			Self::Error(msg) => write!(f, "error: {}", msg),
+		}
+	}
+}
+
+/// The type of value a setting takes.
+#[derive(Clone, Copy)]
+enum Kind {
+	/// `on` or `off` (or a TOML boolean)
+	Bool,
+	/// A non-negative integer
+	Integer,
+	/// One of a fixed set of values
+	Choice(&'static [&'static str]),
+	/// The name of one of the bundled themes
+	Theme,
+	/// Items separated by the given character (or a TOML array), each of
+	/// which has to pass the check
+	List(char, fn(&str) -> bool),
+	/// Any string
+	Text,
+	/// Content policy rules: a TOML array of tables, or the same as JSON
+	Policy,
+}
+
+impl Kind {
+	/// Validates a value, converting it to the string form settings are
+	/// stored in.
+	fn parse(self, value: &toml::Value) -> Result<String, String> {
+		use toml::Value;
+
+		match (self, value) {
+			(Self::Bool, Value::Boolean(val)) => Ok(if *val { "on" } else { "off" }.to_string()),
+			(Self::Bool, Value::String(val)) if val == "on" || val == "off" => Ok(val.clone()),
+			(Self::Bool, _) => Err(format!("expected \"on\" or \"off\", found {}", value)),
+
+			(Self::Integer, Value::Integer(val)) if *val >= 0 => Ok(val.to_string()),
+			(Self::Integer, Value::String(val)) if val.trim().parse::<u32>().is_ok() => Ok(val.trim().to_string()),
+			(Self::Integer, _) => Err(format!("expected a non-negative integer, found {}", value)),
+
+			(Self::Choice(choices), Value::String(val)) if choices.contains(&val.as_str()) => Ok(val.clone()),
+			(Self::Choice(choices), _) => Err(format!("expected one of {}, found {}", choices.join(", "), value)),
+
+			(Self::Theme, Value::String(val)) if themes().contains(val) => Ok(val.clone()),
+			(Self::Theme, _) => Err(format!("expected one of {}, found {}", themes().join(", "), value)),
+
+			(Self::List(separator, check), Value::String(val)) => Self::list(val.split(separator).map(str::trim), separator, check),
+			(Self::List(separator, check), Value::Array(items)) => {
+				let items = items.iter().map(|item| item.as_str().ok_or_else(|| format!("expected a list of strings, found {}", value)));
+				Self::list(items.collect::<Result<Vec<&str>, String>>()?.into_iter(), separator, check)
+			}
+			(Self::List(..), _) => Err(format!("expected a list, found {}", value)),
+
+			(Self::Text, Value::String(val)) => Ok(val.clone()),
+			(Self::Text, _) => Err(format!("expected a string, found {}", value)),
+
+			(Self::Policy, Value::String(val)) => crate::policy::validate(val),
+			(Self::Policy, Value::Array(_)) => crate::policy::validate(&serde_json::to_string(value).map_err(|e| e.to_string())?),
+			(Self::Policy, _) => Err(format!("expected a list of rules, found {}", value)),
+		}
+	}
+
+	fn list<'a>(items: impl Iterator<Item = &'a str>, separator: char, check: fn(&str) -> bool) -> Result<String, String> {
+		let items: Vec<&str> = items.filter(|item| !item.is_empty()).collect();
+		match items.iter().find(|item| !check(item)) {
+			Some(invalid) => Err(format!("invalid item \"{}\"", invalid)),
+			None => Ok(items.join(&separator.to_string())),
 		}
 	}
 }
 
+/// Names of the themes users can choose from.
+fn themes() -> Vec<String> {
+	let mut themes = vec!["system".to_string()];
+	themes.extend(crate::utils::ThemeAssets::iter().filter_map(|file| file.strip_suffix(".css").map(str::to_string)));
+	themes
+}
+
+const POST_SORTS: &[&str] = &["hot", "new", "top", "rising", "controversial"];
+// Reddit also accepts "best", its newer name for "confidence"
+const COMMENT_SORTS: &[&str] = &["confidence", "best", "top", "new", "controversial", "old"];
+
+/// Every setting and the type of value it takes.
+const SETTINGS: &[(&str, Kind)] = &[
+	("LIBREDDIT_SFW_ONLY", Kind::Bool),
+	("LIBREDDIT_DEFAULT_THEME", Kind::Theme),
+	("LIBREDDIT_DEFAULT_FRONT_PAGE", Kind::Choice(&["default", "popular", "all"])),
+	("LIBREDDIT_DEFAULT_LAYOUT", Kind::Choice(&["card", "clean", "compact"])),
+	("LIBREDDIT_DEFAULT_COMMENT_SORT", Kind::Choice(COMMENT_SORTS)),
+	("LIBREDDIT_DEFAULT_POST_SORT", Kind::Choice(POST_SORTS)),
+	("LIBREDDIT_DEFAULT_SHOW_NSFW", Kind::Bool),
+	("LIBREDDIT_DEFAULT_BLUR_NSFW", Kind::Bool),
+	("LIBREDDIT_DEFAULT_USE_HLS", Kind::Bool),
+	("LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION", Kind::Bool),
+	("LIBREDDIT_DEFAULT_WIDE", Kind::Bool),
+	("LIBREDDIT_DEFAULT_HIDE_AWARDS", Kind::Bool),
+	("LIBREDDIT_DEFAULT_SUBSCRIPTIONS", Kind::List('+', crate::utils::is_sub_name)),
+	("LIBREDDIT_DEFAULT_DISABLE_VISIT_REDDIT_CONFIRMATION", Kind::Bool),
+	("LIBREDDIT_DEFAULT_MIN_SCORE", Kind::Integer),
+	("LIBREDDIT_DEFAULT_MIN_RATIO", Kind::Integer),
+	("LIBREDDIT_DEFAULT_POST_TYPES", Kind::Choice(&["all", "media", "text"])),
+	("LIBREDDIT_DEFAULT_HIDE_STICKIED", Kind::Bool),
+	("LIBREDDIT_DEFAULT_TRACK_READ", Kind::Bool),
+	("LIBREDDIT_BANNER", Kind::Text),
+	("LIBREDDIT_ROBOTS_DISABLE_INDEXING", Kind::Bool),
+	("LIBREDDIT_PUSHSHIFT_FRONTEND", Kind::Text),
+	("LIBREDDIT_RATE_LIMIT", Kind::Integer),
+	("LIBREDDIT_RATE_LIMIT_BURST", Kind::Integer),
+	("LIBREDDIT_RATE_LIMIT_IPV6_PREFIX", Kind::Integer),
+	("LIBREDDIT_TRUSTED_PROXIES", Kind::List(',', crate::ratelimit::is_cidr)),
+	("LIBREDDIT_MAX_UPSTREAM_REQUESTS", Kind::Integer),
+	("LIBREDDIT_BASE_PATH", Kind::Text),
+	("LIBREDDIT_CONTENT_SECURITY_POLICY", Kind::Text),
+	("LIBREDDIT_X_FRAME_OPTIONS", Kind::Text),
+	("LIBREDDIT_REFERRER_POLICY", Kind::Text),
+	("LIBREDDIT_STRICT_TRANSPORT_SECURITY", Kind::Text),
+	("LIBREDDIT_EXTRA_HEADERS", Kind::Text),
+	("LIBREDDIT_BLOCKED_SUBREDDITS", Kind::List(',', crate::blocklist::is_pattern)),
+	("LIBREDDIT_BLOCKED_USERS", Kind::List(',', crate::blocklist::is_pattern)),
+	("LIBREDDIT_BLOCKED_MESSAGE", Kind::Text),
+	("LIBREDDIT_CONTENT_POLICY", Kind::Policy),
+	("LIBREDDIT_PROFILES", Kind::Bool),
+	("LIBREDDIT_PROFILES_PATH", Kind::Text),
+];
+
+fn kind(name: &str) -> Option<Kind> {
+	SETTINGS.iter().find(|(setting, _)| *setting == name).map(|(_, kind)| *kind)
+}
+
+/// Validates the value of a user preference by the rules of the setting its
+/// instance default comes from, converting it to the form its cookie holds.
+pub(crate) fn validate_preference(name: &str, value: &toml::Value) -> Result<String, String> {
+	let kind = match name {
+		// None of these has an instance default
+		"autoplay_videos" => Some(Kind::Bool),
+		"filters" => kind("LIBREDDIT_DEFAULT_SUBSCRIPTIONS"),
+		"feeds" => Some(Kind::List(crate::utils::FEED_SEPARATOR, crate::utils::Feed::is_valid)),
+		"content_filters" => Some(Kind::List(crate::content_filter::SEPARATOR, crate::content_filter::ContentFilter::is_valid)),
+		"hidden_posts" | "read_posts" => Some(Kind::List('+', crate::utils::is_post_id)),
+		"bookmarks" => Some(Kind::List(crate::bookmarks::SEPARATOR, crate::bookmarks::Bookmark::is_valid)),
+		"sub_preferences" => Some(Kind::List(crate::utils::SUB_PREFERENCES_SEPARATOR, crate::utils::SubPreferences::is_valid)),
+		_ => kind(&format!("LIBREDDIT_DEFAULT_{}", name.to_uppercase())),
+	};
+	kind.ok_or_else(|| format!("unknown preference {}", name))?.parse(value)
+}
+
 fn get_setting_from_config(name: &str, config: &Config) -> Option<String> {
 	match name {
 		"LIBREDDIT_SFW_ONLY" => config.sfw_only.clone(),
@@ -123,16 +502,200 @@ fn get_setting_from_config(name: &str, config: &Config) -> Option<String> {
 		"LIBREDDIT_DEFAULT_HIDE_AWARDS" => config.default_hide_awards.clone(),
 		"LIBREDDIT_DEFAULT_SUBSCRIPTIONS" => config.default_subscriptions.clone(),
 		"LIBREDDIT_DEFAULT_DISABLE_VISIT_REDDIT_CONFIRMATION" => config.default_disable_visit_reddit_confirmation.clone(),
+		"LIBREDDIT_DEFAULT_MIN_SCORE" => config.default_min_score.clone(),
+		"LIBREDDIT_DEFAULT_MIN_RATIO" => config.default_min_ratio.clone(),
+		"LIBREDDIT_DEFAULT_POST_TYPES" => config.default_post_types.clone(),
+		"LIBREDDIT_DEFAULT_HIDE_STICKIED" => config.default_hide_stickied.clone(),
+		"LIBREDDIT_DEFAULT_TRACK_READ" => config.default_track_read.clone(),
 		"LIBREDDIT_BANNER" => config.banner.clone(),
 		"LIBREDDIT_ROBOTS_DISABLE_INDEXING" => config.robots_disable_indexing.clone(),
 		"LIBREDDIT_PUSHSHIFT_FRONTEND" => config.pushshift.clone(),
+		"LIBREDDIT_RATE_LIMIT" => config.rate_limit.clone(),
+		"LIBREDDIT_RATE_LIMIT_BURST" => config.rate_limit_burst.clone(),
+		"LIBREDDIT_RATE_LIMIT_IPV6_PREFIX" => config.rate_limit_ipv6_prefix.clone(),
+		"LIBREDDIT_TRUSTED_PROXIES" => config.trusted_proxies.clone(),
+		"LIBREDDIT_MAX_UPSTREAM_REQUESTS" => config.max_upstream_requests.clone(),
+		"LIBREDDIT_BASE_PATH" => config.base_path.clone(),
+		"LIBREDDIT_CONTENT_SECURITY_POLICY" => config.content_security_policy.clone(),
+		"LIBREDDIT_X_FRAME_OPTIONS" => config.x_frame_options.clone(),
+		"LIBREDDIT_REFERRER_POLICY" => config.referrer_policy.clone(),
+		"LIBREDDIT_STRICT_TRANSPORT_SECURITY" => config.strict_transport_security.clone(),
+		"LIBREDDIT_EXTRA_HEADERS" => config.extra_headers.clone(),
+		"LIBREDDIT_BLOCKED_SUBREDDITS" => config.blocked_subreddits.clone(),
+		"LIBREDDIT_BLOCKED_USERS" => config.blocked_users.clone(),
+		"LIBREDDIT_BLOCKED_MESSAGE" => config.blocked_message.clone(),
+		"LIBREDDIT_CONTENT_POLICY" => config.content_policy.clone(),
+		"LIBREDDIT_PROFILES" => config.profiles.clone(),
+		"LIBREDDIT_PROFILES_PATH" => config.profiles_path.clone(),
 		_ => None,
 	}
 }
 
+/// Sets the config file given on the command line. Must be called before the
+/// configuration is first used.
+pub fn set_path(path: PathBuf) {
+	let _ = PATH.set(path);
+}
+
+/// Sets the max-age of the default Strict-Transport-Security header, given
+/// on the command line with `--hsts`.
+pub fn set_hsts_max_age(max_age: String) {
+	let _ = HSTS_MAX_AGE.set(max_age);
+}
+
+/// The Strict-Transport-Security header sent unless
+/// LIBREDDIT_STRICT_TRANSPORT_SECURITY overrides it.
+pub(crate) fn default_strict_transport_security() -> String {
+	format!("max-age={}", HSTS_MAX_AGE.get().map_or(DEFAULT_HSTS_MAX_AGE, String::as_str))
+}
+
+/// Determines which config file to use: the one given through `--config` or
+/// LIBREDDIT_CONFIG, or else the first one that exists of `./libreddit.toml`,
+/// `$XDG_CONFIG_HOME/libreddit/libreddit.toml` (`~/.config` by default) and
+/// `/etc/libreddit/libreddit.toml`.
+fn path() -> Option<PathBuf> {
+	if let Some(path) = PATH.get().cloned().or_else(|| var_os("LIBREDDIT_CONFIG").map(PathBuf::from)) {
+		return Some(path);
+	}
+
+	let xdg_config_home = var_os("XDG_CONFIG_HOME")
+		.map(PathBuf::from)
+		.filter(|dir| dir.is_absolute())
+		.or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+	[
+		Some(PathBuf::from("libreddit.toml")),
+		xdg_config_home.map(|dir| dir.join("libreddit").join("libreddit.toml")),
+		Some(PathBuf::from("/etc/libreddit/libreddit.toml")),
+	]
+	.into_iter()
+	.flatten()
+	.find(|path| path.is_file())
+}
+
+/// Returns a snapshot of the current configuration.
+pub(crate) fn current() -> Arc<Config> {
+	CONFIG.read().map_or_else(|poisoned| poisoned.into_inner().clone(), |config| config.clone())
+}
+
+/// Returns the errors found while loading the configuration, if any.
+pub(crate) fn load_error() -> Option<String> {
+	let errors: Vec<String> = current()
+		.problems
+		.iter()
+		.filter_map(|problem| match problem {
+			Problem::Error(e) => Some(e.clone()),
+			Problem::Warning(_) => None,
+		})
+		.collect();
+
+	(!errors.is_empty()).then(|| errors.join("; "))
+}
+
+/// Returns when the configuration was last reloaded, if ever.
+pub(crate) fn reloaded_at() -> Option<OffsetDateTime> {
+	RELOADED_AT.read().ok().and_then(|reloaded| *reloaded)
+}
+
 /// Retrieves setting from environment variable or config file.
 pub(crate) fn get_setting(name: &str) -> Option<String> {
-	get_setting_from_config(name, &CONFIG)
+	get_setting_from_config(name, &current())
+}
+
+/// Loads the configuration again and swaps it in. If the config file has
+/// errors, the previous configuration stays in place; invalid environment
+/// variables are dropped and reported, as they are at startup.
+pub(crate) fn reload() {
+	let mut config = Config::load();
+
+	if config.file_errors {
+		eprintln!("Keeping the previous configuration");
+		return;
+	}
+
+	keep_startup_settings(&mut config, &current());
+	if let Ok(mut current) = CONFIG.write() {
+		*current = Arc::new(config);
+	}
+	if let Ok(mut reloaded) = RELOADED_AT.write() {
+		*reloaded = Some(OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()));
+	}
+	println!("Reloaded configuration");
+}
+
+/// Settings that are only read at startup. A reload keeps their old values,
+/// so that the rest of the code doesn't see values the server isn't using.
+fn keep_startup_settings(config: &mut Config, previous: &Config) {
+	for (name, value, old) in [
+		("LIBREDDIT_BASE_PATH", &mut config.base_path, &previous.base_path),
+		("LIBREDDIT_MAX_UPSTREAM_REQUESTS", &mut config.max_upstream_requests, &previous.max_upstream_requests),
+	] {
+		if value != old {
+			let problem = Problem::Warning(format!("{} changed, restart Libreddit to apply it", name));
+			eprintln!("{}", problem);
+			config.problems.push(problem);
+			value.clone_from(old);
+		}
+	}
+}
+
+/// Prints the configuration that results from the config file and the
+/// environment, followed by every problem found in it. Returns whether
+/// the configuration is free of errors.
+pub fn check() -> bool {
+	let path = path();
+	match &path {
+		Some(path) => eprintln!("Using config file {}", path.display()),
+		None => eprintln!("No config file found, only environment variables are used"),
+	}
+
+	let config = Config::load_from(path.as_deref());
+
+	match toml::to_string(&config) {
+		Ok(toml) => print!("{}", toml),
+		Err(e) => eprintln!("Failed to print the configuration: {}", e),
+	}
+
+	if config.problems.is_empty() {
+		eprintln!("No problems found");
+	}
+	for problem in &config.problems {
+		eprintln!("{}", problem);
+	}
+
+	!config.problems.iter().any(|problem| matches!(problem, Problem::Error(_)))
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup() {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut hangup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP signal handler");
+	while hangup.recv().await.is_some() {
+		reload();
+	}
+}
+
+/// Periodically checks the config file for changes and reloads the
+/// configuration when it changed.
+pub async fn watch() {
+	let modified = || {
+		let path = path();
+		let modified = path.as_ref().and_then(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok());
+		(path, modified)
+	};
+	let mut last: (Option<PathBuf>, Option<SystemTime>) = modified();
+
+	loop {
+		tokio::time::sleep(WATCH_INTERVAL).await;
+
+		let current = modified();
+		if current != last {
+			last = current;
+			reload();
+		}
+	}
 }
 
 #[cfg(test)]
@@ -179,3 +742,113 @@ fn test_alt_env_config_precedence() {
 fn test_default_subscriptions() {
 	assert_eq!(get_setting("LIBREDDIT_DEFAULT_SUBSCRIPTIONS"), Some("news+bestof".into()));
 }
+
+#[test]
+#[sealed_test]
+fn test_load_error() {
+	assert_eq!(load_error(), None);
+	write("libreddit.toml", "LIBREDDIT_BANNER = ").unwrap();
+	assert!(matches!(&Config::load().problems[..], [Problem::Error(e)] if e.starts_with("Failed to parse libreddit.toml")));
+}
+
+#[test]
+#[sealed_test(env = [("LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION", "on")])]
+fn test_hide_hls_notification_env() {
+	assert_eq!(get_setting("LIBREDDIT_DEFAULT_HIDE_HLS_NOTIFICATION"), Some("on".into()));
+}
+
+#[test]
+fn test_kinds() {
+	use toml::Value;
+	let string = |val: &str| Value::String(val.to_string());
+
+	assert_eq!(Kind::Bool.parse(&Value::Boolean(true)), Ok("on".into()));
+	assert_eq!(Kind::Bool.parse(&string("off")), Ok("off".into()));
+	assert!(Kind::Bool.parse(&string("yes")).is_err());
+
+	assert_eq!(Kind::Integer.parse(&Value::Integer(60)), Ok("60".into()));
+	assert!(Kind::Integer.parse(&Value::Integer(-1)).is_err());
+	assert!(Kind::Integer.parse(&string("many")).is_err());
+
+	assert_eq!(Kind::Choice(POST_SORTS).parse(&string("top")), Ok("top".into()));
+	assert!(Kind::Choice(POST_SORTS).parse(&string("best")).is_err());
+	assert_eq!(Kind::Theme.parse(&string("nord")), Ok("nord".into()));
+	assert!(Kind::Theme.parse(&string("nrod")).is_err());
+
+	let subs = kind("LIBREDDIT_DEFAULT_SUBSCRIPTIONS").unwrap();
+	assert_eq!(subs.parse(&string("rust+linux")), Ok("rust+linux".into()));
+	assert_eq!(subs.parse(&Value::Array(vec![string("rust"), string("linux")])), Ok("rust+linux".into()));
+	assert!(subs.parse(&string("rust+r/linux")).is_err());
+}
+
+#[test]
+#[sealed_test(env = [("LIBREDDIT_DEFAULT_POST_SORT", "best")])]
+fn test_problems() {
+	write(
+		"libreddit.toml",
+		"LIBREDDIT_DEFAULT_THEME = \"nrod\"\nLIBREDDIT_DEFAUL_LAYOUT = \"card\"\nLIBREDDIT_SFW_ONLY = true",
+	)
+	.unwrap();
+	let config = Config::load();
+
+	assert_eq!(config.sfw_only, Some("on".into()));
+	assert_eq!(config.default_theme, None);
+	assert_eq!(config.default_post_sort, None);
+	assert_eq!(config.problems.iter().filter(|problem| matches!(problem, Problem::Error(_))).count(), 2);
+	assert!(config
+		.problems
+		.contains(&Problem::Warning("Unknown setting LIBREDDIT_DEFAUL_LAYOUT in libreddit.toml".into())));
+}
+
+#[test]
+#[sealed_test(env = [("LIBREDDIT_BANNER_FILE", "banner.txt"), ("LIBREDDIT_PUSHSHIFT_FRONTEND_FILE", "missing.txt")])]
+fn test_file_env() {
+	write("banner.txt", "Hello from a file\n").unwrap();
+	let config = Config::load();
+	assert_eq!(config.banner, Some("Hello from a file".into()));
+	assert_eq!(config.pushshift, None);
+	assert!(matches!(&config.problems[..], [Problem::Error(e)] if e.contains("LIBREDDIT_PUSHSHIFT_FRONTEND_FILE")));
+}
+
+#[test]
+#[sealed_test(env = [("LIBREDDIT_CONFIG", "elsewhere/libreddit.toml")])]
+fn test_config_path() {
+	write("libreddit.toml", r#"LIBREDDIT_BANNER = "here""#).unwrap();
+	std::fs::create_dir("elsewhere").unwrap();
+	write("elsewhere/libreddit.toml", r#"LIBREDDIT_BANNER = "elsewhere""#).unwrap();
+	assert_eq!(get_setting("LIBREDDIT_BANNER"), Some("elsewhere".into()));
+}
+
+#[test]
+#[sealed_test(env = [("LIBREDDIT_DEFAULT_POST_SORT", "best")])]
+fn test_reload_with_env_errors() {
+	write("libreddit.toml", r#"LIBREDDIT_BANNER = "before""#).unwrap();
+	assert_eq!(get_setting("LIBREDDIT_BANNER"), Some("before".into()));
+
+	// An invalid environment variable doesn't stop the file from being applied
+	write("libreddit.toml", r#"LIBREDDIT_BANNER = "after""#).unwrap();
+	reload();
+	assert_eq!(get_setting("LIBREDDIT_BANNER"), Some("after".into()));
+	assert!(load_error().is_some_and(|e| e.contains("LIBREDDIT_DEFAULT_POST_SORT")));
+
+	// A broken config file keeps the previous configuration
+	write("libreddit.toml", "LIBREDDIT_BANNER = ").unwrap();
+	reload();
+	assert_eq!(get_setting("LIBREDDIT_BANNER"), Some("after".into()));
+}
+
+#[test]
+#[sealed_test]
+fn test_reload_keeps_startup_settings() {
+	write("libreddit.toml", "LIBREDDIT_BASE_PATH = \"/reddit\"\nLIBREDDIT_BANNER = \"before\"").unwrap();
+	assert_eq!(get_setting("LIBREDDIT_BASE_PATH"), Some("/reddit".into()));
+
+	write("libreddit.toml", "LIBREDDIT_BASE_PATH = \"/libreddit\"\nLIBREDDIT_BANNER = \"after\"").unwrap();
+	reload();
+	assert_eq!(get_setting("LIBREDDIT_BANNER"), Some("after".into()));
+	assert_eq!(get_setting("LIBREDDIT_BASE_PATH"), Some("/reddit".into()));
+	assert!(current()
+		.problems
+		.iter()
+		.any(|problem| matches!(problem, Problem::Warning(w) if w.starts_with("LIBREDDIT_BASE_PATH changed"))));
+}
@@ -0,0 +1,186 @@
+// CRATES
+use crate::{
+	policy::Action,
+	utils::{setting, Post},
+};
+use hyper::{Body, Request};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use regex::{Regex, RegexBuilder};
+use std::{
+	fmt,
+	hash::{Hash, Hasher},
+};
+
+/// Separates the filters in the `content_filters` cookie.
+pub const SEPARATOR: char = '|';
+
+// Keeps regexes written by users from taking up much memory
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+
+/// What part of a post or comment a filter matches against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+	Title,
+	Flair,
+	Domain,
+	Comment,
+}
+
+impl Field {
+	pub const ALL: [Self; 4] = [Self::Title, Self::Flair, Self::Domain, Self::Comment];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Title => "title",
+			Self::Flair => "flair",
+			Self::Domain => "domain",
+			Self::Comment => "comment",
+		}
+	}
+
+	fn parse(name: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|field| field.name() == name)
+	}
+}
+
+/// A personal filter, hiding or collapsing posts whose title, flair or domain
+/// match it, or comments whose body does.
+///
+/// Patterns are matched case-insensitively as keywords, or as a regex when
+/// written between slashes, like `/\bspoilers?\b/`. Domain filters also match
+/// subdomains.
+#[derive(Clone, Debug)]
+pub struct ContentFilter {
+	pub field: Field,
+	pub action: Action,
+	pub pattern: String,
+	regex: Regex,
+}
+
+impl ContentFilter {
+	pub fn new(field: Field, action: Action, pattern: &str) -> Result<Self, String> {
+		let pattern = pattern.trim();
+		if pattern.is_empty() {
+			return Err("The filter is empty".to_string());
+		}
+
+		let regex = match (field, pattern.strip_prefix('/').and_then(|pattern| pattern.strip_suffix('/'))) {
+			(Field::Domain, _) => format!(r"(^|\.){}$", regex::escape(&pattern.to_ascii_lowercase())),
+			(_, Some(regex)) if !regex.is_empty() => regex.to_string(),
+			_ => regex::escape(pattern),
+		};
+		let regex = RegexBuilder::new(&regex)
+			.case_insensitive(true)
+			.size_limit(REGEX_SIZE_LIMIT)
+			.build()
+			.map_err(|e| format!("Invalid regex: {}", e))?;
+
+		Ok(Self {
+			field,
+			action,
+			pattern: pattern.to_string(),
+			regex,
+		})
+	}
+
+	/// Parses a filter from the form it is kept in within the
+	/// `content_filters` cookie, `field:action:pattern` with the pattern
+	/// percent-encoded.
+	pub fn parse(filter: &str) -> Option<Self> {
+		let mut parts = filter.splitn(3, ':');
+		let field = Field::parse(parts.next()?)?;
+		let action = match parts.next()? {
+			"hide" => Action::Hide,
+			"collapse" => Action::Collapse,
+			_ => return None,
+		};
+		let pattern = percent_decode_str(parts.next()?).decode_utf8().ok()?;
+		Self::new(field, action, &pattern).ok()
+	}
+
+	/// Whether `filter` is a valid filter in its cookie form.
+	pub fn is_valid(filter: &str) -> bool {
+		Self::parse(filter).is_some()
+	}
+
+	/// Reads the filters from the `content_filters` cookie.
+	pub fn all(req: &Request<Body>) -> Vec<Self> {
+		setting(req, "content_filters").split(SEPARATOR).filter_map(Self::parse).collect()
+	}
+
+	fn matches_post(&self, post: &Post) -> bool {
+		match self.field {
+			Field::Title => self.regex.is_match(&post.title),
+			Field::Flair => self.regex.is_match(&post.flair.text),
+			Field::Domain => self.regex.is_match(&post.domain),
+			Field::Comment => false,
+		}
+	}
+
+	/// Describes the filter in the notice collapsed items are shown behind.
+	fn notice(&self) -> String {
+		format!("Collapsed by your {} filter \"{}\"", self.field.name(), self.pattern)
+	}
+}
+
+impl fmt::Display for ContentFilter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let action = match self.action {
+			Action::Hide => "hide",
+			Action::Collapse => "collapse",
+		};
+		write!(f, "{}:{}:{}", self.field.name(), action, utf8_percent_encode(&self.pattern, NON_ALPHANUMERIC))
+	}
+}
+
+// Preferences are hashed, and compiled regexes cannot be
+impl Hash for ContentFilter {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.to_string().hash(state);
+	}
+}
+
+/// Checks a post against the filters. Returns the action of the first filter
+/// it matches, with the notice to show if it is collapsed.
+pub fn check_post(filters: &[ContentFilter], post: &Post) -> Option<(Action, String)> {
+	let filter = filters.iter().find(|filter| filter.matches_post(post))?;
+	Some((filter.action, filter.notice()))
+}
+
+/// Checks the text of a comment against the comment filters, like
+/// [`check_post`].
+pub fn check_comment(filters: &[ContentFilter], body: &str) -> Option<(Action, String)> {
+	let filter = filters.iter().find(|filter| filter.field == Field::Comment && filter.regex.is_match(body))?;
+	Some((filter.action, filter.notice()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		let filter = ContentFilter::parse("title:collapse:%2F%5Cbspoilers%3F%5Cb%2F").unwrap();
+		assert_eq!(filter.pattern, r"/\bspoilers?\b/");
+		assert!(filter.regex.is_match("Finale SPOILER thread"));
+		assert!(!filter.regex.is_match("despoilers"));
+		assert_eq!(
+			ContentFilter::new(Field::Title, Action::Collapse, r"/\bspoilers?\b/").unwrap().to_string(),
+			"title:collapse:%2F%5Cbspoilers%3F%5Cb%2F"
+		);
+
+		assert!(ContentFilter::parse("flair:hide:Meme").unwrap().regex.is_match("memes"));
+		assert!(ContentFilter::parse("title:hide:%2F(%2F").is_none());
+		assert!(ContentFilter::parse("title:delete:x").is_none());
+		assert!(ContentFilter::parse("author:hide:x").is_none());
+	}
+
+	#[test]
+	fn test_domain() {
+		let filter = ContentFilter::new(Field::Domain, Action::Hide, "Example.com").unwrap();
+		assert!(filter.regex.is_match("example.com"));
+		assert!(filter.regex.is_match("i.example.com"));
+		assert!(!filter.regex.is_match("notexample.com"));
+		assert!(!filter.regex.is_match("example.com.evil"));
+	}
+}
@@ -1,9 +1,11 @@
 // Handler for post duplicates.
 
+use crate::blocklist;
 use crate::client::json;
+use crate::content_filter::ContentFilter;
 use crate::server::RequestExt;
 use crate::subreddit::{can_access_quarantine, quarantine};
-use crate::utils::{error, filter_posts, get_filters, nsfw_landing, parse_post, template, Post, Preferences};
+use crate::utils::{error, filter_posts, get_filters, nsfw_landing, parse_post, template, ListingFilter, Post, Preferences};
 
 use askama::Template;
 use hyper::{Body, Request, Response};
@@ -57,6 +59,10 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 
+	if blocklist::subreddit_blocked(&sub) {
+		return blocklist::unavailable(req).await;
+	}
+
 	// Log the request in debugging mode
 	#[cfg(debug_assertions)]
 	dbg!(req.param("id").unwrap_or_default());
@@ -67,6 +73,10 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 		Ok(response) => {
 			let post = parse_post(&response[0]["data"]["children"][0]).await;
 
+			if blocklist::post_blocked(&post) {
+				return blocklist::unavailable(req).await;
+			}
+
 			let req_url = req.uri().to_string();
 			// Return landing page if this post if this Reddit deems this post
 			// NSFW, but we have also disabled the display of NSFW content
@@ -76,7 +86,7 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 			}
 
 			let filters = get_filters(&req);
-			let (duplicates, num_posts_filtered, all_posts_filtered) = parse_duplicates(&response[1], &filters).await;
+			let (duplicates, num_posts_filtered, all_posts_filtered) = parse_duplicates(&response[1], &filters, &ContentFilter::all(&req)).await;
 
 			// These are the values for the "before=", "after=", and "sort="
 			// query params, respectively.
@@ -221,7 +231,7 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 }
 
 // DUPLICATES
-async fn parse_duplicates(json: &serde_json::Value, filters: &HashSet<String>) -> (Vec<Post>, u64, bool) {
+async fn parse_duplicates(json: &serde_json::Value, filters: &HashSet<String>, content_filters: &[ContentFilter]) -> (Vec<Post>, u64, bool) {
 	let post_duplicates: &Vec<Value> = &json["data"]["children"].as_array().map_or(Vec::new(), ToOwned::to_owned);
 	let mut duplicates: Vec<Post> = Vec::new();
 
@@ -231,6 +241,9 @@ async fn parse_duplicates(json: &serde_json::Value, filters: &HashSet<String>) -
 		duplicates.push(post);
 	}
 
-	let (num_posts_filtered, all_posts_filtered) = filter_posts(&mut duplicates, filters);
+	// Blocked posts are left out without a trace
+	duplicates.retain(|post| !blocklist::post_blocked(post));
+
+	let (num_posts_filtered, all_posts_filtered) = filter_posts(&mut duplicates, filters, content_filters, &ListingFilter::default());
 	(duplicates, num_posts_filtered, all_posts_filtered)
 }
@@ -0,0 +1,115 @@
+// CRATES
+use crate::{config, server};
+use hyper::{Body, Request, Response};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::{
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+// How long a failed upstream request keeps the instance from being ready,
+// unless a later request succeeds. Readiness recovers on its own afterwards,
+// since a probe that never lets traffic through would never see Reddit
+// succeed again.
+const UPSTREAM_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+static UPSTREAM: Lazy<Mutex<UpstreamHealth>> = Lazy::new(|| Mutex::new(UpstreamHealth::default()));
+
+/// Outcome of the latest requests to Reddit.
+#[derive(Default)]
+struct UpstreamHealth {
+	last_success: Option<Instant>,
+	last_failure: Option<(Instant, String)>,
+}
+
+impl UpstreamHealth {
+	/// Why Reddit is considered unreachable at `now`, if it is.
+	fn failure(&self, now: Instant) -> Option<&str> {
+		let (failed, reason) = self.last_failure.as_ref()?;
+		let recovered = self.last_success.is_some_and(|success| success > *failed);
+		(!recovered && now.saturating_duration_since(*failed) < UPSTREAM_FAILURE_WINDOW).then_some(reason.as_str())
+	}
+}
+
+/// Records that a request to Reddit got a usable response.
+pub fn note_upstream_success() {
+	if let Ok(mut upstream) = UPSTREAM.lock() {
+		upstream.last_success = Some(Instant::now());
+	}
+}
+
+/// Records that a request to Reddit failed: it couldn't be sent, Reddit had
+/// an internal error or refused to answer because of rate limiting.
+pub fn note_upstream_failure(reason: &str) {
+	if let Ok(mut upstream) = UPSTREAM.lock() {
+		upstream.last_failure = Some((Instant::now(), reason.to_string()));
+	}
+}
+
+// /healthz
+/// Tells orchestrators that the process is alive. Never touches Reddit.
+pub async fn healthz(_req: Request<Body>) -> Result<Response<Body>, String> {
+	Ok(json_response(200, &json!({ "status": "ok" })))
+}
+
+// /readyz
+/// Tells load balancers and orchestrators whether this instance should be
+/// sent traffic. Fails while the server is draining for shutdown, when the
+/// config file couldn't be loaded, or when requests to Reddit are failing.
+/// Only looks at requests made for users, so probes don't spend Reddit quota.
+pub async fn readyz(_req: Request<Body>) -> Result<Response<Body>, String> {
+	let (last_success, upstream_failure) = match UPSTREAM.lock() {
+		Ok(upstream) => (upstream.last_success, upstream.failure(Instant::now()).map(str::to_string)),
+		Err(_) => (None, None),
+	};
+
+	let reason = if server::draining() {
+		Some("draining".to_string())
+	} else if let Some(e) = config::load_error() {
+		Some(format!("config: {}", e))
+	} else {
+		upstream_failure.map(|e| format!("upstream: {}", e))
+	};
+
+	let checks = json!({
+		"draining": server::draining(),
+		"config": config::load_error().is_none(),
+		"upstream_last_success_secs": last_success.map(|success| success.elapsed().as_secs()),
+	});
+
+	Ok(match reason {
+		Some(reason) => json_response(503, &json!({ "status": "unavailable", "reason": reason, "checks": checks })),
+		None => json_response(200, &json!({ "status": "ok", "checks": checks })),
+	})
+}
+
+fn json_response(status: u16, body: &Value) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header("content-type", "application/json")
+		.header("cache-control", "no-store")
+		.body(body.to_string().into())
+		.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_upstream_failure() {
+		let start = Instant::now();
+		let mut upstream = UpstreamHealth::default();
+		assert_eq!(upstream.failure(start), None);
+
+		upstream.last_success = Some(start);
+		upstream.last_failure = Some((start + Duration::from_secs(1), "Too many requests".to_string()));
+		assert_eq!(upstream.failure(start + Duration::from_secs(2)), Some("Too many requests"));
+
+		// Forgotten after a while, or as soon as a request succeeds
+		assert_eq!(upstream.failure(start + UPSTREAM_FAILURE_WINDOW + Duration::from_secs(2)), None);
+		upstream.last_success = Some(start + Duration::from_secs(2));
+		assert_eq!(upstream.failure(start + Duration::from_secs(3)), None);
+	}
+}
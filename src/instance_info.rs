@@ -1,5 +1,5 @@
 use crate::{
-	config::{Config, CONFIG},
+	config::{self, Config},
 	server::RequestExt,
 	utils::{ErrorTemplate, Preferences},
 };
@@ -10,10 +10,9 @@ use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
-// This is the local static that is intialized at runtime (technically at
-// the first request to the info endpoint) and contains the data
-// retrieved from the info endpoint.
-pub(crate) static INSTANCE_INFO: Lazy<InstanceInfo> = Lazy::new(InstanceInfo::new);
+// This is the local static that is intialized at runtime (forced at startup
+// in main) and contains the time the instance was deployed.
+pub(crate) static DEPLOY_DATE: Lazy<OffsetDateTime> = Lazy::new(|| OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()));
 
 /// Handles instance info endpoint
 pub async fn instance_info(req: Request<Body>) -> Result<Response<Body>, String> {
@@ -40,7 +39,7 @@ pub async fn instance_info(req: Request<Body>) -> Result<Response<Body>, String>
 }
 
 fn info_json() -> Result<Response<Body>, Error> {
-	if let Ok(body) = serde_json::to_string(&*INSTANCE_INFO) {
+	if let Ok(body) = serde_json::to_string(&InstanceInfo::new()) {
 		Response::builder().status(200).header("content-type", "application/json").body(body.into())
 	} else {
 		Response::builder()
@@ -51,7 +50,7 @@ fn info_json() -> Result<Response<Body>, Error> {
 }
 
 fn info_yaml() -> Result<Response<Body>, Error> {
-	if let Ok(body) = serde_yaml::to_string(&*INSTANCE_INFO) {
+	if let Ok(body) = serde_yaml::to_string(&InstanceInfo::new()) {
 		// We can use `application/yaml` as media type, though there is no guarantee
 		// that browsers will honor it. But we'll do it anyway. See:
 		// https://github.com/ietf-wg-httpapi/mediatypes/blob/main/draft-ietf-httpapi-yaml-mediatypes.md#media-type-applicationyaml-application-yaml
@@ -68,12 +67,12 @@ fn info_txt() -> Result<Response<Body>, Error> {
 	Response::builder()
 		.status(200)
 		.header("content-type", "text/plain")
-		.body(Body::from(INSTANCE_INFO.to_string(StringType::Raw)))
+		.body(Body::from(InstanceInfo::new().to_string(StringType::Raw)))
 }
 fn info_html(req: Request<Body>) -> Result<Response<Body>, Error> {
 	let message = MessageTemplate {
 		title: String::from("Instance information"),
-		body: INSTANCE_INFO.to_string(StringType::Html),
+		body: InstanceInfo::new().to_string(StringType::Html),
 		prefs: Preferences::new(&req),
 		url: req.uri().to_string(),
 	}
@@ -88,6 +87,8 @@ pub(crate) struct InstanceInfo {
 	deploy_date: String,
 	compile_mode: String,
 	deploy_unix_ts: i64,
+	reload_date: Option<String>,
+	reload_unix_ts: Option<i64>,
 	config: Config,
 }
 
@@ -96,13 +97,19 @@ impl InstanceInfo {
 		Self {
 			crate_version: env!("CARGO_PKG_VERSION").to_string(),
 			git_commit: env!("GIT_HASH").to_string(),
-			deploy_date: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()).to_string(),
+			deploy_date: DEPLOY_DATE.to_string(),
 			#[cfg(debug_assertions)]
 			compile_mode: "Debug".into(),
 			#[cfg(not(debug_assertions))]
 			compile_mode: "Release".into(),
-			deploy_unix_ts: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()).unix_timestamp(),
-			config: CONFIG.clone(),
+			deploy_unix_ts: DEPLOY_DATE.unix_timestamp(),
+			reload_date: config::reloaded_at().map(|date| date.to_string()),
+			reload_unix_ts: config::reloaded_at().map(OffsetDateTime::unix_timestamp),
+			config: Config {
+				// A path on the server's filesystem, which is none of the public's business
+				profiles_path: None,
+				..(*config::current()).clone()
+			},
 		}
 	}
 	fn to_table(&self) -> String {
@@ -120,10 +127,28 @@ impl InstanceInfo {
 				["Git commit", &self.git_commit],
 				["Deploy date", &self.deploy_date],
 				["Deploy timestamp", &self.deploy_unix_ts.to_string()],
+				["Config reload date", &convert(&self.reload_date)],
+				["Config reload timestamp", &convert(&self.reload_unix_ts.map(|ts| ts.to_string()))],
 				["Compile mode", &self.compile_mode],
 				["SFW only", &convert(&self.config.sfw_only)],
 				["Pushshift frontend", &convert(&self.config.pushshift)],
 				//TODO: fallback to crate::config::DEFAULT_PUSHSHIFT_FRONTEND
+				["Rate limit", &convert(&self.config.rate_limit)],
+				["Rate limit burst", &convert(&self.config.rate_limit_burst)],
+				["Rate limit IPv6 prefix", &convert(&self.config.rate_limit_ipv6_prefix)],
+				["Trusted proxies", &convert(&self.config.trusted_proxies)],
+				["Max upstream requests", &convert(&self.config.max_upstream_requests)],
+				["Base path", &convert(&self.config.base_path)],
+				["Content-Security-Policy", &convert(&self.config.content_security_policy)],
+				["X-Frame-Options", &convert(&self.config.x_frame_options)],
+				["Referrer-Policy", &convert(&self.config.referrer_policy)],
+				["Strict-Transport-Security", &convert(&self.config.strict_transport_security)],
+				["Extra headers", &convert(&self.config.extra_headers)],
+				["Blocked subreddits", &convert(&self.config.blocked_subreddits)],
+				["Blocked users", &convert(&self.config.blocked_users)],
+				["Blocked message", &convert(&self.config.blocked_message)],
+				["Content policy", &convert(&self.config.content_policy)],
+				["Server-side profiles", &convert(&self.config.profiles)],
 			])
 			.with_header_row(["Settings"]),
 		);
@@ -142,6 +167,11 @@ impl InstanceInfo {
 				["Use HLS", &convert(&self.config.default_use_hls)],
 				["Hide HLS notification", &convert(&self.config.default_hide_hls_notification)],
 				["Subscriptions", &convert(&self.config.default_subscriptions)],
+				["Minimum score", &convert(&self.config.default_min_score)],
+				["Minimum upvote ratio", &convert(&self.config.default_min_ratio)],
+				["Post types", &convert(&self.config.default_post_types)],
+				["Hide stickied posts", &convert(&self.config.default_hide_stickied)],
+				["Track read posts", &convert(&self.config.default_track_read)],
 			])
 			.with_header_row(["Default preferences"]),
 		);
@@ -155,9 +185,27 @@ impl InstanceInfo {
                 Git commit: {}\n
                 Deploy date: {}\n
                 Deploy timestamp: {}\n
+                Config reload date: {:?}\n
+                Config reload timestamp: {:?}\n
                 Compile mode: {}\n
 				SFW only: {:?}\n
 				Pushshift frontend: {:?}\n
+				Rate limit: {:?}\n
+				Rate limit burst: {:?}\n
+				Rate limit IPv6 prefix: {:?}\n
+				Trusted proxies: {:?}\n
+				Max upstream requests: {:?}\n
+				Base path: {:?}\n
+				Content-Security-Policy: {:?}\n
+				X-Frame-Options: {:?}\n
+				Referrer-Policy: {:?}\n
+				Strict-Transport-Security: {:?}\n
+				Extra headers: {:?}\n
+				Blocked subreddits: {:?}\n
+				Blocked users: {:?}\n
+				Blocked message: {:?}\n
+				Content policy: {:?}\n
+				Server-side profiles: {:?}\n
                 Config:\n
                     Banner: {:?}\n
                     Hide awards: {:?}\n
@@ -171,14 +219,37 @@ impl InstanceInfo {
                     Default blur NSFW: {:?}\n
                     Default use HLS: {:?}\n
                     Default hide HLS notification: {:?}\n
-                    Default subscriptions: {:?}\n",
+                    Default subscriptions: {:?}\n
+                    Default minimum score: {:?}\n
+                    Default minimum upvote ratio: {:?}\n
+                    Default post types: {:?}\n
+                    Default hide stickied posts: {:?}\n
+                    Default track read posts: {:?}\n",
 					self.crate_version,
 					self.git_commit,
 					self.deploy_date,
 					self.deploy_unix_ts,
+					self.reload_date,
+					self.reload_unix_ts,
 					self.compile_mode,
 					self.config.sfw_only,
 					self.config.pushshift,
+					self.config.rate_limit,
+					self.config.rate_limit_burst,
+					self.config.rate_limit_ipv6_prefix,
+					self.config.trusted_proxies,
+					self.config.max_upstream_requests,
+					self.config.base_path,
+					self.config.content_security_policy,
+					self.config.x_frame_options,
+					self.config.referrer_policy,
+					self.config.strict_transport_security,
+					self.config.extra_headers,
+					self.config.blocked_subreddits,
+					self.config.blocked_users,
+					self.config.blocked_message,
+					self.config.content_policy,
+					self.config.profiles,
 					self.config.banner,
 					self.config.default_hide_awards,
 					self.config.default_theme,
@@ -192,6 +263,11 @@ impl InstanceInfo {
 					self.config.default_use_hls,
 					self.config.default_hide_hls_notification,
 					self.config.default_subscriptions,
+					self.config.default_min_score,
+					self.config.default_min_ratio,
+					self.config.default_post_types,
+					self.config.default_hide_stickied,
+					self.config.default_track_read,
 				)
 			}
 			StringType::Html => self.to_table(),
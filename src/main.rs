@@ -3,13 +3,22 @@
 #![allow(clippy::cmp_owned)]
 
 // Reference local files
+mod access_log;
+mod blocklist;
+mod bookmarks;
 mod config;
+mod content_filter;
 mod duplicates;
+mod health;
 mod instance_info;
+mod policy;
 mod post;
+mod profile;
+mod ratelimit;
 mod search;
 mod settings;
 mod subreddit;
+mod tls;
 mod user;
 mod utils;
 
@@ -23,6 +32,7 @@ mod client;
 use client::{canonical_path, proxy};
 use once_cell::sync::Lazy;
 use server::RequestExt;
+use std::time::Duration;
 use utils::{error, redirect, ThemeAssets};
 
 mod server;
@@ -89,6 +99,12 @@ async fn resource(body: &str, content_type: &str, cache: bool) -> Result<Respons
 	Ok(res)
 }
 
+async fn manifest() -> Result<Response<Body>, String> {
+	// Point the start URL and icons at the base path
+	let manifest = include_str!("../static/manifest.json").replace("\": \"/", &format!("\": \"{}/", utils::base_path()));
+	resource(&manifest, "application/json", false).await
+}
+
 async fn style() -> Result<Response<Body>, String> {
 	let mut res = include_str!("../static/style.css").to_string();
 	for file in ThemeAssets::iter() {
@@ -96,6 +112,10 @@ async fn style() -> Result<Response<Body>, String> {
 		let theme = ThemeAssets::get(file.as_ref()).unwrap();
 		res.push_str(std::str::from_utf8(theme.data.as_ref()).unwrap());
 	}
+
+	// Fonts are referenced by absolute URLs
+	let res = server::rebase_css_urls(&res, &utils::base_path());
+
 	Ok(
 		Response::builder()
 			.status(200)
@@ -115,7 +135,7 @@ async fn main() {
 			Arg::new("redirect-https")
 				.short('r')
 				.long("redirect-https")
-				.help("Redirect all HTTP requests to HTTPS (no longer functional)")
+				.help("Redirect all HTTP requests to HTTPS (requires --tls-cert and --tls-key)")
 				.num_args(0),
 		)
 		.arg(
@@ -123,7 +143,7 @@ async fn main() {
 				.short('a')
 				.long("address")
 				.value_name("ADDRESS")
-				.help("Sets address to listen on")
+				.help("Sets address to listen on (use unix:PATH for a Unix domain socket)")
 				.default_value("0.0.0.0")
 				.num_args(1),
 		)
@@ -138,6 +158,73 @@ async fn main() {
 				.action(ArgAction::Set)
 				.num_args(1),
 		)
+		.arg(
+			Arg::new("socket-mode")
+				.long("socket-mode")
+				.value_name("MODE")
+				.help("Permissions of the Unix domain socket, in octal (e.g. 660)")
+				.value_parser(|mode: &str| u32::from_str_radix(mode, 8).map_err(|_| format!("{} is not an octal file mode", mode)))
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("tls-cert")
+				.long("tls-cert")
+				.value_name("FILE")
+				.help("PEM certificate chain to serve HTTPS with (reloaded when changed)")
+				.requires("tls-key")
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("tls-key")
+				.long("tls-key")
+				.value_name("FILE")
+				.help("PEM private key of the TLS certificate (reloaded when changed)")
+				.requires("tls-cert")
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("http-port")
+				.long("http-port")
+				.value_name("PORT")
+				.help("Port of the plain HTTP listener redirecting to HTTPS")
+				.default_value("80")
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("shutdown-timeout")
+				.long("shutdown-timeout")
+				.value_name("SECONDS")
+				.help("How long to let in-flight requests finish on SIGTERM or SIGINT")
+				.default_value("10")
+				.value_parser(clap::value_parser!(u64))
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("watch-config")
+				.long("watch-config")
+				.help("Reload the configuration whenever libreddit.toml changes")
+				.action(ArgAction::SetTrue),
+		)
+		.arg(
+			Arg::new("access-log")
+				.long("access-log")
+				.value_name("FORMAT")
+				.help("Log every request to standard output, as JSON or logfmt")
+				.value_parser(["json", "logfmt"])
+				.num_args(1),
+		)
+		.arg(
+			Arg::new("access-log-client-ip")
+				.long("access-log-client-ip")
+				.help("Include client IPs in the access log")
+				.action(ArgAction::SetTrue),
+		)
+		.arg(
+			Arg::new("access-log-query")
+				.long("access-log-query")
+				.help("Include query strings (e.g. search terms) in the access log")
+				.action(ArgAction::SetTrue),
+		)
 		.arg(
 			Arg::new("hsts")
 				.short('H')
@@ -147,13 +234,39 @@ async fn main() {
 				.default_value("604800")
 				.num_args(1),
 		)
+		.arg(
+			Arg::new("config")
+				.short('c')
+				.long("config")
+				.value_name("PATH")
+				.env("LIBREDDIT_CONFIG")
+				.help("Config file (default: the first of ./libreddit.toml, $XDG_CONFIG_HOME/libreddit/libreddit.toml and /etc/libreddit/libreddit.toml)")
+				.global(true)
+				.num_args(1),
+		)
+		.subcommand(Command::new("check-config").about("Print the effective configuration and every problem found in it"))
 		.get_matches();
 
+	if let Some(path) = matches.get_one::<String>("config") {
+		config::set_path(path.into());
+	}
+	if let Some(max_age) = matches.get_one::<String>("hsts") {
+		config::set_hsts_max_age(max_age.clone());
+	}
+
+	if matches.subcommand_matches("check-config").is_some() {
+		std::process::exit(if config::check() { 0 } else { 1 });
+	}
+
 	let address = matches.get_one::<String>("address").unwrap();
 	let port = matches.get_one::<String>("port").unwrap();
-	let hsts = matches.get_one("hsts").map(|m: &String| m.as_str());
 
-	let listener = [address, ":", port].concat();
+	// Unix domain sockets have no port
+	let listener = if address.starts_with("unix:") {
+		address.to_owned()
+	} else {
+		[address, ":", port].concat()
+	};
 
 	println!("Starting Libreddit...");
 
@@ -165,27 +278,58 @@ async fn main() {
 	// evaluate the configuration to avoid paying penalty at first request.
 
 	Lazy::force(&config::CONFIG);
-	Lazy::force(&instance_info::INSTANCE_INFO);
-
-	// Define default headers (added to all responses)
-	app.default_headers = headers! {
-		"Referrer-Policy" => "no-referrer",
-		"X-Content-Type-Options" => "nosniff",
-		"X-Frame-Options" => "DENY",
-		"Content-Security-Policy" => "default-src 'none'; font-src 'self'; script-src 'self' blob:; manifest-src 'self'; media-src 'self' data: blob: about:; style-src 'self' 'unsafe-inline'; base-uri 'none'; img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; connect-src 'self'; worker-src blob:;"
-	};
+	Lazy::force(&instance_info::DEPLOY_DATE);
+
+	// Reload the configuration on SIGHUP and, if asked to, when the file changes
+	#[cfg(unix)]
+	tokio::spawn(config::reload_on_sighup());
+	if matches.get_flag("watch-config") {
+		tokio::spawn(config::watch());
+	}
+
+	// Define the security headers (added to all responses)
+	app.config_headers = utils::security_headers;
+
+	app.socket_mode = matches.get_one::<u32>("socket-mode").copied();
+	app.shutdown_timeout = Duration::from_secs(*matches.get_one::<u64>("shutdown-timeout").unwrap());
 
-	if let Some(expire_time) = hsts {
-		if let Ok(val) = HeaderValue::from_str(&format!("max-age={}", expire_time)) {
-			app.default_headers.insert("Strict-Transport-Security", val);
+	// Unix domain sockets are only served plain, and have no host to redirect to
+	if address.starts_with("unix:") && (matches.contains_id("tls-cert") || matches.get_flag("redirect-https")) {
+		eprintln!("--tls-cert and --redirect-https need a TCP address, not a Unix domain socket");
+		std::process::exit(1);
+	}
+
+	// Serve HTTPS directly if a certificate is given
+	if let (Some(cert), Some(key)) = (matches.get_one::<String>("tls-cert"), matches.get_one::<String>("tls-key")) {
+		app.tls = Some(tls::acceptor(cert.into(), key.into()).unwrap_or_else(|e| panic!("Cannot load TLS certificate: {}", e)));
+
+		if matches.get_flag("redirect-https") {
+			let http_port = matches.get_one::<String>("http-port").unwrap();
+			app.redirect_http = Some([address, ":", http_port].concat());
 		}
+	} else if matches.get_flag("redirect-https") {
+		eprintln!("Ignoring --redirect-https: HTTPS is only served with --tls-cert and --tls-key");
 	}
 
+	app.access_log = matches.get_one::<String>("access-log").map(|format| access_log::AccessLog {
+		format: if format == "json" { access_log::Format::Json } else { access_log::Format::Logfmt },
+		client_ip: matches.get_flag("access-log-client-ip"),
+		query: matches.get_flag("access-log-query"),
+	});
+
+	// Serve the app under a path prefix, if configured
+	app.base_path = utils::base_path();
+
+	// Render errors raised by the server itself with the themed error page
+	app.error_page = |req, status, msg| utils::error_with_status(req, status, msg).boxed();
+
+	// Derive page ETags from the effective preferences of each request
+	app.etag_seed = utils::etag_seed;
+	app.prepare = profile::attach;
+
 	// Read static files
 	app.at("/style.css").get(|_| style().boxed());
-	app
-		.at("/manifest.json")
-		.get(|_| resource(include_str!("../static/manifest.json"), "application/json", false).boxed());
+	app.at("/manifest.json").get(|_| manifest().boxed());
 	app.at("/robots.txt").get(|_| {
 		resource(
 			if match config::get_setting("LIBREDDIT_ROBOTS_DISABLE_INDEXING") {
@@ -213,6 +357,10 @@ async fn main() {
 		.at("/hls.min.js")
 		.get(|_| resource(include_str!("../static/hls.min.js"), "text/javascript", false).boxed());
 
+	// Health checks for load balancers and orchestrators
+	app.at("/healthz").get(|r| health::healthz(r).boxed());
+	app.at("/readyz").get(|r| health::readyz(r).boxed());
+
 	// Proxy media through Libreddit
 	app.at("/vid/:id/:size").get(|r| proxy(r, "https://v.redd.it/{id}/DASH_{size}").boxed());
 	app.at("/hls/:id/*path").get(|r| proxy(r, "https://v.redd.it/{id}/{path}").boxed());
@@ -244,6 +392,13 @@ async fn main() {
 	app.at("/settings").get(|r| settings::get(r).boxed()).post(|r| settings::set(r).boxed());
 	app.at("/settings/restore").get(|r| settings::restore(r).boxed());
 	app.at("/settings/update").get(|r| settings::update(r).boxed());
+	app.at("/settings/profile").post(|r| settings::profile(r).boxed());
+	app.at("/settings/export").get(|r| settings::export(r).boxed());
+	app.at("/settings/import").post(|r| settings::import(r).boxed());
+	app.at("/settings/feeds").post(|r| settings::feeds(r).boxed());
+	app.at("/settings/content_filters").post(|r| settings::content_filters(r).boxed());
+	app.at("/settings/history").post(|r| settings::history(r).boxed());
+	app.at("/settings/subscriptions").post(|r| settings::import_subscriptions(r).boxed());
 
 	// Subreddit services
 	app
@@ -259,6 +414,17 @@ async fn main() {
 	app.at("/r/:sub/unsubscribe").post(|r| subreddit::subscriptions_filters(r).boxed());
 	app.at("/r/:sub/filter").post(|r| subreddit::subscriptions_filters(r).boxed());
 	app.at("/r/:sub/unfilter").post(|r| subreddit::subscriptions_filters(r).boxed());
+	app.at("/r/:sub/preferences").post(|r| subreddit::preferences(r).boxed());
+
+	// Saved posts and comments
+	app.at("/saved").get(|r| bookmarks::page(r).boxed());
+	app.at("/saved/export").get(|r| bookmarks::export(r).boxed());
+	app.at("/save").post(|r| bookmarks::save(r).boxed());
+	app.at("/unsave").post(|r| bookmarks::save(r).boxed());
+
+	// Hide posts from listings
+	app.at("/hide/:id").post(|r| post::hide(r).boxed());
+	app.at("/unhide/:id").post(|r| post::hide(r).boxed());
 
 	app.at("/r/:sub/comments/:id").get(|r| post::item(r).boxed());
 	app.at("/r/:sub/comments/:id/:title").get(|r| post::item(r).boxed());
@@ -289,6 +455,10 @@ async fn main() {
 
 	app.at("/r/:sub/:sort").get(|r| subreddit::community(r).boxed());
 
+	// Custom feeds
+	app.at("/f/:feed").get(|r| subreddit::community(r).boxed());
+	app.at("/f/:feed/:sort").get(|r| subreddit::community(r).boxed());
+
 	// Front page
 	app.at("/").get(|r| subreddit::community(r).boxed());
 
@@ -342,4 +512,7 @@ async fn main() {
 	if let Err(e) = server.await {
 		eprintln!("Server error: {}", e);
 	}
+
+	// Write out profile changes still waiting to be saved
+	profile::flush();
 }
@@ -0,0 +1,206 @@
+// CRATES
+use crate::{config::Config, utils::Post};
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+// Shown on collapsed and hidden items when a rule gives no reason
+const DEFAULT_NOTICE: &str = "Hidden by this instance's content policy";
+
+/// What happens to a post or comment that matches a rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+	/// Posts are removed from listings; comments have their body replaced by
+	/// the notice.
+	#[default]
+	Hide,
+	/// The item is shown collapsed behind the notice.
+	Collapse,
+}
+
+/// A content policy rule as written in `LIBREDDIT_CONTENT_POLICY`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	title: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	body: Option<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	domains: Vec<String>,
+	#[serde(default)]
+	action: Action,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	reason: Option<String>,
+}
+
+/// A rule with its regexes compiled.
+struct Rule {
+	title: Option<Regex>,
+	body: Option<Regex>,
+	domains: Vec<String>,
+	action: Action,
+	notice: String,
+}
+
+impl Rule {
+	fn compile(spec: RuleSpec) -> Result<Self, String> {
+		let regex = |pattern: Option<String>| -> Result<Option<Regex>, String> {
+			pattern
+				.map(|pattern| {
+					RegexBuilder::new(&pattern)
+						.case_insensitive(true)
+						.build()
+						.map_err(|e| format!("invalid regex \"{}\": {}", pattern, e))
+				})
+				.transpose()
+		};
+
+		if spec.title.is_none() && spec.body.is_none() && spec.domains.is_empty() {
+			return Err("a rule needs a title, body or domains to match".to_string());
+		}
+
+		Ok(Self {
+			title: regex(spec.title)?,
+			body: regex(spec.body)?,
+			domains: spec.domains.iter().map(|domain| domain.trim().trim_start_matches("*.").to_ascii_lowercase()).collect(),
+			action: spec.action,
+			notice: spec.reason.filter(|reason| !reason.is_empty()).unwrap_or_else(|| DEFAULT_NOTICE.to_string()),
+		})
+	}
+
+	/// Whether the rule matches a comment, by the comment's plain `text` or
+	/// the links in its rendered `html`.
+	fn matches_comment(&self, text: &str, html: &str) -> bool {
+		static LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r#"href="(?:https?:)?//([^/"?#:]+)"#).unwrap());
+
+		self.body.as_ref().is_some_and(|re| re.is_match(text)) || (!self.domains.is_empty() && LINK.captures_iter(html).any(|link| self.matches_domain(&link[1])))
+	}
+
+	fn matches_domain(&self, domain: &str) -> bool {
+		let domain = domain.to_ascii_lowercase();
+		self
+			.domains
+			.iter()
+			.any(|listed| domain == *listed || domain.strip_suffix(listed.as_str()).is_some_and(|sub| sub.ends_with('.')))
+	}
+}
+
+/// Validates the JSON form of `LIBREDDIT_CONTENT_POLICY`, returning it
+/// normalized.
+pub fn validate(rules: &str) -> Result<String, String> {
+	let specs: Vec<RuleSpec> = serde_json::from_str(rules).map_err(|e| format!("expected a list of rules: {}", e))?;
+	for (i, spec) in specs.iter().enumerate() {
+		Rule::compile(spec.clone()).map_err(|e| format!("rule {}: {}", i + 1, e))?;
+	}
+	serde_json::to_string(&specs).map_err(|e| e.to_string())
+}
+
+// The compiled rules, along with the configuration they were compiled from
+type Rules = Arc<Vec<Rule>>;
+static RULES: Mutex<Option<(Arc<Config>, Rules)>> = Mutex::new(None);
+
+/// Returns the rules of the current configuration. They are only compiled
+/// again once the configuration is reloaded.
+fn rules() -> Rules {
+	let config = crate::config::current();
+
+	let Ok(mut cached) = RULES.lock() else {
+		return Arc::new(compile(&config));
+	};
+
+	match &*cached {
+		Some((compiled_from, rules)) if Arc::ptr_eq(compiled_from, &config) => rules.clone(),
+		_ => {
+			let rules = Arc::new(compile(&config));
+			*cached = Some((config, rules.clone()));
+			rules
+		}
+	}
+}
+
+fn compile(config: &Config) -> Vec<Rule> {
+	let specs: Vec<RuleSpec> = config.content_policy.as_deref().and_then(|rules| serde_json::from_str(rules).ok()).unwrap_or_default();
+	specs.into_iter().filter_map(|spec| Rule::compile(spec).ok()).collect()
+}
+
+/// Checks a post against the content policy. Returns the action of the first
+/// rule its title, body or domain matches, with the notice to show.
+pub fn check_post(post: &Post) -> Option<(Action, String)> {
+	let rules = rules();
+	let rule = rules.iter().find(|rule| {
+		rule.title.as_ref().is_some_and(|re| re.is_match(&post.title)) || rule.body.as_ref().is_some_and(|re| re.is_match(&post.text)) || rule.matches_domain(&post.domain)
+	})?;
+	Some((rule.action, rule.notice.clone()))
+}
+
+/// Checks a comment against the content policy. Body rules match the plain
+/// text of the comment and domain rules the links in its rendered `html`.
+pub fn check_comment(text: &str, html: &str) -> Option<(Action, String)> {
+	let rules = rules();
+	let rule = rules.iter().find(|rule| rule.matches_comment(text, html))?;
+	Some((rule.action, rule.notice.clone()))
+}
+
+/// Applies the content policy to a listing: posts matching a hide rule are
+/// removed and those matching a collapse rule get a notice to be shown
+/// behind.
+pub fn apply(posts: &mut Vec<Post>) {
+	posts.retain_mut(|post| match check_post(post) {
+		Some((Action::Hide, _)) => false,
+		Some((Action::Collapse, notice)) => {
+			post.notice = notice;
+			true
+		}
+		None => true,
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rule(json: &str) -> Rule {
+		Rule::compile(serde_json::from_str(json).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn test_validate() {
+		assert_eq!(
+			validate(r#"[{"title": "spoiler", "action": "collapse", "reason": "Spoilers"}]"#),
+			Ok(r#"[{"title":"spoiler","action":"collapse","reason":"Spoilers"}]"#.to_string())
+		);
+		assert_eq!(
+			validate(r#"[{"domains": ["example.com"]}]"#),
+			Ok(r#"[{"domains":["example.com"],"action":"hide"}]"#.to_string())
+		);
+		assert!(validate(r#"[{"title": "("}]"#).unwrap_err().starts_with("rule 1: invalid regex"));
+		assert!(validate(r#"[{"action": "hide"}]"#).is_err());
+		assert!(validate(r#"[{"title": "a", "action": "delete"}]"#).is_err());
+		assert!(validate(r#"[{"titel": "a"}]"#).is_err());
+	}
+
+	#[test]
+	fn test_matches_comment() {
+		let text = "See [this](https://example.com/a) & <that>";
+		let html = r#"<div class="md"><p>See <a href="https://example.com/a">this</a> &amp; &lt;that&gt;</p></div>"#;
+
+		// Body rules see the text as written, not the markup around it
+		assert!(rule(r#"{"body": "& <that>"}"#).matches_comment(text, html));
+		assert!(!rule(r#"{"body": "href|amp;|class="}"#).matches_comment(text, html));
+		assert!(rule(r#"{"domains": ["example.com"]}"#).matches_comment(text, html));
+	}
+
+	#[test]
+	fn test_matches_domain() {
+		let rule = rule(r#"{"domains": ["Example.com", "*.bad.net"]}"#);
+		assert!(rule.matches_domain("example.com"));
+		assert!(rule.matches_domain("www.EXAMPLE.com"));
+		assert!(!rule.matches_domain("notexample.com"));
+		assert!(rule.matches_domain("cdn.bad.net"));
+		assert!(rule.matches_domain("bad.net"));
+		assert_eq!(rule.notice, DEFAULT_NOTICE);
+	}
+}
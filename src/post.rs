@@ -1,17 +1,23 @@
 // CRATES
+use crate::blocklist;
 use crate::client::json;
 use crate::config::get_setting;
+use crate::content_filter;
+use crate::policy::Action;
 use crate::server::RequestExt;
 use crate::subreddit::{can_access_quarantine, quarantine};
 use crate::utils::{
-	error, format_num, get_filters, nsfw_landing, param, parse_post, rewrite_urls, setting, template, time, val, Author, Awards, Comment, Flair, FlairPart, Post, Preferences,
+	error, format_num, get_filters, is_post_id, local_path, nsfw_landing, param, parse_post, post_ids, push_post_id, redirect, rewrite_urls, setting, template, time, val,
+	Author, Awards, Comment, Flair, FlairPart, Post, Preferences, SubPreferences, MAX_HIDDEN_POSTS, MAX_READ_POSTS,
 };
+use crate::{profile, settings};
 use hyper::{Body, Request, Response};
 
 use askama::Template;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use std::collections::HashSet;
+use std::sync::Arc;
 
 // STRUCTS
 #[derive(Template)]
@@ -20,7 +26,7 @@ struct PostTemplate {
 	comments: Vec<Comment>,
 	post: Post,
 	sort: String,
-	prefs: Preferences,
+	prefs: Arc<Preferences>,
 	single_thread: bool,
 	url: String,
 	url_without_query: String,
@@ -36,10 +42,17 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	let quarantined = can_access_quarantine(&req, &sub);
 	let url = req.uri().to_string();
 
+	// Refuse posts in blocked subreddits or by blocked users right away, when
+	// the URL tells where the post is from
+	if blocklist::subreddit_blocked(&sub) || blocklist::user_blocked(&req.param("name").unwrap_or_default()) {
+		return blocklist::unavailable(req).await;
+	}
+
 	// Set sort to sort query parameter
 	let sort = param(&path, "sort").unwrap_or_else(|| {
-		// Grab default comment sort method from Cookies
-		let default_sort = setting(&req, "comment_sort");
+		// Grab default comment sort method of the subreddit, or else from Cookies
+		let sub_sort = SubPreferences::get(&req, &sub).comment_sort;
+		let default_sort = if sub_sort.is_empty() { setting(&req, "comment_sort") } else { sub_sort };
 
 		// If there's no sort query but there's a default sort, set sort to default_sort
 		if default_sort.is_empty() {
@@ -64,6 +77,10 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 			// Parse the JSON into Post and Comment structs
 			let post = parse_post(&response[0]["data"]["children"][0]).await;
 
+			if blocklist::post_blocked(&post) {
+				return blocklist::unavailable(req).await;
+			}
+
 			let req_url = req.uri().to_string();
 			// Return landing page if this post if this Reddit deems this post
 			// NSFW, but we have also disabled the display of NSFW content
@@ -77,22 +94,37 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 				None => String::new(),
 			};
 
+			// Every comment shares the preferences, which are costly to build
+			let prefs = Arc::new(Preferences::for_sub(&req, &sub));
 			let comments = match query.as_str() {
-				"" => parse_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &req),
-				_ => query_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &query, &req),
+				"" => parse_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &prefs),
+				_ => query_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &query, &prefs),
 			};
 
+			let id = post.id.clone();
+
 			// Use the Post and Comment structs to generate a website to show users
-			template(PostTemplate {
+			let mut response = template(PostTemplate {
 				comments,
 				post,
 				url_without_query: url.clone().trim_end_matches(&format!("?q={query}&type=comment")).to_string(),
 				sort,
-				prefs: Preferences::new(&req),
+				prefs,
 				single_thread,
 				url: req_url,
 				comment_query: query,
-			})
+			})?;
+
+			// Remember the post as read, to dim it in listings
+			if setting(&req, "track_read") == "on" {
+				let mut read = post_ids(&req, "read_posts", MAX_READ_POSTS);
+				if read.last() != Some(&id) {
+					push_post_id(&mut read, &id, MAX_READ_POSTS);
+					settings::save(profile::token(&req).as_deref(), &mut response, &[("read_posts".to_string(), Some(read.join("+")))])?;
+				}
+			}
+
+			Ok(response)
 		}
 		// If the Reddit API returns an error, exit and send error page to user
 		Err(msg) => {
@@ -106,9 +138,46 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	}
 }
 
+// Hide a post from listings, or show it again
+pub async fn hide(req: Request<Body>) -> Result<Response<Body>, String> {
+	let id = req.param("id").unwrap_or_default();
+	if !is_post_id(&id) {
+		return Err("Invalid post ID".to_string());
+	}
+
+	let token = profile::token(&req);
+	let mut hidden = post_ids(&req, "hidden_posts", MAX_HIDDEN_POSTS);
+	if req.uri().path().starts_with("/unhide/") {
+		hidden.retain(|hidden| *hidden != id);
+	} else {
+		push_post_id(&mut hidden, &id, MAX_HIDDEN_POSTS);
+	}
+
+	// Go back to the listing the post was hidden from
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let path = url::form_urlencoded::parse(&body_bytes)
+		.find(|(key, _)| key == "redirect")
+		.map(|(_, path)| path.into_owned());
+
+	let mut response = redirect(local_path(path));
+	settings::save(
+		token.as_deref(),
+		&mut response,
+		&[("hidden_posts".to_string(), (!hidden.is_empty()).then(|| hidden.join("+")))],
+	)?;
+	Ok(response)
+}
+
 // COMMENTS
 
-fn parse_comments(json: &serde_json::Value, post_link: &str, post_author: &str, highlighted_comment: &str, filters: &HashSet<String>, req: &Request<Body>) -> Vec<Comment> {
+fn parse_comments(
+	json: &serde_json::Value,
+	post_link: &str,
+	post_author: &str,
+	highlighted_comment: &str,
+	filters: &HashSet<String>,
+	prefs: &Arc<Preferences>,
+) -> Vec<Comment> {
 	// Parse the comment JSON into a Vector of Comments
 	let comments = json["data"]["children"].as_array().map_or(Vec::new(), std::borrow::ToOwned::to_owned);
 
@@ -118,15 +187,16 @@ fn parse_comments(json: &serde_json::Value, post_link: &str, post_author: &str,
 		.map(|comment| {
 			let data = &comment["data"];
 			let replies: Vec<Comment> = if data["replies"].is_object() {
-				parse_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, req)
+				parse_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, prefs)
 			} else {
 				Vec::new()
 			};
-			build_comment(&comment, data, replies, post_link, post_author, highlighted_comment, filters, req)
+			build_comment(&comment, data, replies, post_link, post_author, highlighted_comment, filters, prefs)
 		})
 		.collect()
 }
 
+#[allow(clippy::too_many_arguments)]
 fn query_comments(
 	json: &serde_json::Value,
 	post_link: &str,
@@ -134,7 +204,7 @@ fn query_comments(
 	highlighted_comment: &str,
 	filters: &HashSet<String>,
 	query: &str,
-	req: &Request<Body>,
+	prefs: &Arc<Preferences>,
 ) -> Vec<Comment> {
 	let comments = json["data"]["children"].as_array().map_or(Vec::new(), std::borrow::ToOwned::to_owned);
 	let mut results = Vec::new();
@@ -144,10 +214,10 @@ fn query_comments(
 
 		// If this comment contains replies, handle those too
 		if data["replies"].is_object() {
-			results.append(&mut query_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, query, req))
+			results.append(&mut query_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, query, prefs))
 		}
 
-		let c = build_comment(&comment, data, Vec::new(), post_link, post_author, highlighted_comment, filters, req);
+		let c = build_comment(&comment, data, Vec::new(), post_link, post_author, highlighted_comment, filters, prefs);
 		if c.body.to_lowercase().contains(&query.to_lowercase()) {
 			results.push(c);
 		}
@@ -164,7 +234,7 @@ fn build_comment(
 	post_author: &str,
 	highlighted_comment: &str,
 	filters: &HashSet<String>,
-	req: &Request<Body>,
+	prefs: &Arc<Preferences>,
 ) -> Comment {
 	let id = val(comment, "id");
 
@@ -215,7 +285,11 @@ fn build_comment(
 		},
 		distinguished: val(comment, "distinguished"),
 	};
-	let is_filtered = filters.contains(&["u_", author.name.as_str()].concat());
+	// Comments matching the user's own content filters are shown as filtered,
+	// or collapsed behind a notice
+	let text = val(comment, "body");
+	let content_filtered = content_filter::check_comment(&prefs.content_filters, &text);
+	let is_filtered = filters.contains(&["u_", author.name.as_str()].concat()) || matches!(content_filtered, Some((Action::Hide, _)));
 
 	// Many subreddits have a default comment posted about the sub's rules etc.
 	// Many libreddit users do not wish to see this kind of comment by default.
@@ -223,7 +297,24 @@ fn build_comment(
 	// collapse stickied moderator comments.
 	let is_moderator_comment = data["distinguished"].as_str().unwrap_or_default() == "moderator";
 	let is_stickied = data["stickied"].as_bool().unwrap_or_default();
-	let collapsed = (is_moderator_comment && is_stickied) || is_filtered;
+	let mut collapsed = (is_moderator_comment && is_stickied) || is_filtered;
+
+	// Comments the instance's content policy hides lose their body but keep
+	// their place in the thread, so that their replies still show up
+	let (body, mut notice) = match crate::policy::check_comment(&text, &body) {
+		Some((Action::Hide, notice)) => (String::new(), notice),
+		Some((Action::Collapse, notice)) => {
+			collapsed = true;
+			(body, notice)
+		}
+		None => (body, String::new()),
+	};
+	if let Some((Action::Collapse, filter_notice)) = content_filtered {
+		collapsed = true;
+		if notice.is_empty() {
+			notice = filter_notice;
+		}
+	}
 
 	Comment {
 		id,
@@ -247,7 +338,8 @@ fn build_comment(
 		awards,
 		collapsed,
 		is_filtered,
+		notice,
 		more_count,
-		prefs: Preferences::new(req),
+		prefs: Arc::clone(prefs),
 	}
 }
@@ -0,0 +1,287 @@
+// CRATES
+use crate::{config::get_setting, server::RequestExt};
+use hyper::{Body, Request};
+use once_cell::sync::Lazy;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fs,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, RwLock,
+	},
+	time::Duration,
+};
+
+/// Name of the cookie holding the token of the profile a browser is linked to.
+pub const COOKIE: &str = "profile";
+
+// Where profiles are stored unless LIBREDDIT_PROFILES_PATH is set
+const DEFAULT_PATH: &str = "profiles.json";
+
+// Profiles are created without signing in, so their number is capped to keep
+// the file from growing without bounds
+const MAX_PROFILES: usize = 10_000;
+
+// Largest total length of the names and values of a profile's settings
+const MAX_PROFILE_SIZE: usize = 64 * 1024;
+
+// Changes are written to disk this long after the first of them, together
+// with any made in the meantime
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// The settings of a profile, keyed by cookie name.
+pub type Profile = BTreeMap<String, String>;
+
+/// The profiles, along with the file they were read from.
+struct Store {
+	path: PathBuf,
+	profiles: HashMap<String, Profile>,
+}
+
+static STORE: Lazy<RwLock<Option<Store>>> = Lazy::new(|| RwLock::new(None));
+
+// Whether the profiles have changed since they were last written to disk
+static SAVE_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Held while writing the profiles, so that two writes never share the
+// temporary file
+static WRITING: Mutex<()> = Mutex::new(());
+
+/// Whether server-side profiles are enabled through LIBREDDIT_PROFILES.
+pub fn enabled() -> bool {
+	get_setting("LIBREDDIT_PROFILES").is_some_and(|enabled| enabled == "on")
+}
+
+fn path() -> PathBuf {
+	get_setting("LIBREDDIT_PROFILES_PATH")
+		.filter(|path| !path.is_empty())
+		.map_or_else(|| PathBuf::from(DEFAULT_PATH), PathBuf::from)
+}
+
+/// Runs `f` on the profiles, reading them from disk first if they have not
+/// been yet or LIBREDDIT_PROFILES_PATH changed.
+fn with_store<T>(f: impl FnOnce(&mut Store) -> T) -> T {
+	let path = path();
+	let mut store = STORE.write().unwrap_or_else(std::sync::PoisonError::into_inner);
+	if store.as_ref().is_none_or(|store| store.path != path) {
+		// Don't lose changes still waiting to be written to the old path
+		if let Some(old) = store.as_ref().filter(|_| SAVE_PENDING.swap(false, Ordering::AcqRel)) {
+			let _ = save(&old.path, &old.profiles);
+		}
+		let profiles = match fs::read_to_string(&path) {
+			Ok(file) => serde_json::from_str(&file).unwrap_or_else(|e| {
+				eprintln!("Failed to parse profiles from {}: {}", path.display(), e);
+				HashMap::new()
+			}),
+			Err(_) => HashMap::new(),
+		};
+		*store = Some(Store { path, profiles });
+	}
+	f(store.as_mut().expect("profiles are loaded"))
+}
+
+/// Runs `f` on the profiles without blocking other readers.
+fn read<T>(f: impl FnOnce(&Store) -> T) -> T {
+	{
+		let store = STORE.read().unwrap_or_else(std::sync::PoisonError::into_inner);
+		if let Some(store) = store.as_ref().filter(|store| store.path == path()) {
+			return f(store);
+		}
+	}
+	with_store(|store| f(store))
+}
+
+/// Writes the profiles to a temporary file and moves it over the old one, so
+/// that a crash never leaves a truncated file behind.
+fn save(path: &Path, profiles: &HashMap<String, Profile>) -> Result<(), String> {
+	fn write(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create(true).truncate(true);
+		#[cfg(unix)]
+		std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+		let tmp = path.with_extension("tmp");
+		options.open(&tmp)?.write_all(contents)?;
+		fs::rename(tmp, path)
+	}
+
+	let _writing = WRITING.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+	let contents = serde_json::to_vec(profiles).map_err(|e| e.to_string())?;
+	write(path, &contents).map_err(|e| {
+		eprintln!("Failed to save profiles to {}: {}", path.display(), e);
+		"Failed to save the profile".to_string()
+	})
+}
+
+/// Marks the profiles as changed. They are written to disk after SAVE_DELAY
+/// on a blocking thread, so that neither the async workers nor the handler
+/// making the change wait for the disk.
+fn schedule_save() {
+	if SAVE_PENDING.swap(true, Ordering::AcqRel) {
+		return;
+	}
+	match tokio::runtime::Handle::try_current() {
+		Ok(runtime) => {
+			runtime.spawn(async {
+				tokio::time::sleep(SAVE_DELAY).await;
+				let _ = tokio::task::spawn_blocking(flush).await;
+			});
+		}
+		Err(_) => flush(),
+	}
+}
+
+/// Writes the profiles to disk if they have changed since they last were.
+/// Blocks, so call it from a blocking thread or once the server has stopped.
+pub fn flush() {
+	if !SAVE_PENDING.swap(false, Ordering::AcqRel) {
+		return;
+	}
+	let store = STORE.read().unwrap_or_else(std::sync::PoisonError::into_inner);
+	if let Some(store) = store.as_ref() {
+		if save(&store.path, &store.profiles).is_err() {
+			// Try again with the next change or at shutdown
+			SAVE_PENDING.store(true, Ordering::Release);
+		}
+	}
+}
+
+// Total length of the names and values of a profile's settings
+fn size(profile: &Profile) -> usize {
+	profile.iter().map(|(name, value)| name.len() + value.len()).sum()
+}
+
+/// Whether `token` has the form of a profile token.
+pub fn is_token(token: &str) -> bool {
+	token.len() == 32 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// The token and profile a request's browser is linked to, looked up once per
+// request by `attach`
+#[derive(Clone)]
+struct Linked(Option<(String, Arc<Profile>)>);
+
+/// Looks up the profile the request's browser is linked to and keeps it with
+/// the request, so that reading settings doesn't go through the store each time.
+pub fn attach(req: &mut Request<Body>) {
+	let linked = lookup(req);
+	req.extensions_mut().insert(Linked(linked));
+}
+
+fn lookup(req: &Request<Body>) -> Option<(String, Arc<Profile>)> {
+	if !enabled() {
+		return None;
+	}
+	let token = req.cookie(COOKIE)?.value().to_string();
+	if !is_token(&token) {
+		return None;
+	}
+	find(&token).map(|profile| (token, Arc::new(profile)))
+}
+
+/// Returns the token of the profile the request's browser is linked to, if
+/// profiles are enabled and that profile exists.
+pub fn token(req: &Request<Body>) -> Option<String> {
+	match req.extensions().get::<Linked>() {
+		Some(Linked(linked)) => linked.as_ref().map(|(token, _)| token.clone()),
+		None => {
+			if !enabled() {
+				return None;
+			}
+			let token = req.cookie(COOKIE)?.value().to_string();
+			(is_token(&token) && exists(&token)).then_some(token)
+		}
+	}
+}
+
+/// Whether a profile with this token exists.
+pub fn exists(token: &str) -> bool {
+	read(|store| store.profiles.contains_key(token))
+}
+
+/// Returns the profile the request's browser is linked to, if any.
+pub fn get(req: &Request<Body>) -> Option<Arc<Profile>> {
+	match req.extensions().get::<Linked>() {
+		Some(Linked(linked)) => linked.as_ref().map(|(_, profile)| Arc::clone(profile)),
+		None => lookup(req).map(|(_, profile)| profile),
+	}
+}
+
+/// Returns the profile with this token, if it exists.
+pub fn find(token: &str) -> Option<Profile> {
+	read(|store| store.profiles.get(token).cloned())
+}
+
+/// Creates a profile holding `settings` and returns its token.
+pub fn create(settings: Profile) -> Result<String, String> {
+	with_store(|store| {
+		if store.profiles.len() >= MAX_PROFILES {
+			return Err("This instance has reached its limit of profiles".to_string());
+		}
+		if size(&settings) > MAX_PROFILE_SIZE {
+			return Err("These settings are too large for a profile".to_string());
+		}
+		let token = format!("{:032x}", rand::random::<u128>());
+		store.profiles.insert(token.clone(), settings);
+		schedule_save();
+		Ok(token)
+	})
+}
+
+/// Applies changes to a profile: `Some` sets a setting, `None` removes it.
+/// Changes that would make the profile larger than MAX_PROFILE_SIZE are
+/// refused as a whole.
+pub fn update(token: &str, changes: impl IntoIterator<Item = (String, Option<String>)>) -> Result<(), String> {
+	with_store(|store| {
+		let Some(profile) = store.profiles.get_mut(token) else {
+			return Ok(());
+		};
+		let mut changed = profile.clone();
+		for (name, value) in changes {
+			match value {
+				Some(value) => changed.insert(name, value),
+				None => changed.remove(&name),
+			};
+		}
+		if size(&changed) > MAX_PROFILE_SIZE && size(&changed) > size(profile) {
+			return Err("These settings are too large for a profile".to_string());
+		}
+		if changed != *profile {
+			*profile = changed;
+			schedule_save();
+		}
+		Ok(())
+	})
+}
+
+/// Deletes a profile, returning its settings.
+pub fn delete(token: &str) -> Result<Option<Profile>, String> {
+	with_store(|store| {
+		let profile = store.profiles.remove(token);
+		if profile.is_some() {
+			schedule_save();
+		}
+		Ok(profile)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_token() {
+		assert!(is_token(&format!("{:032x}", rand::random::<u128>())));
+		assert!(!is_token("0123"));
+		assert!(!is_token("../../../../../../../etc/passwd00"));
+	}
+
+	#[test]
+	fn test_size() {
+		let profile = Profile::from([("theme".to_string(), "dark".to_string()), ("wide".to_string(), "on".to_string())]);
+		assert_eq!(size(&profile), 15);
+		assert_eq!(size(&Profile::new()), 0);
+	}
+}
@@ -0,0 +1,358 @@
+// CRATES
+use crate::config::get_setting;
+use hyper::{header::HeaderValue, HeaderMap};
+use once_cell::sync::Lazy;
+use std::{
+	collections::{hash_map::RandomState, HashMap},
+	hash::BuildHasher,
+	net::{IpAddr, Ipv6Addr},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+// Client IPs are only kept as hashes keyed with a random per-process key, so
+// they can neither be recovered from memory nor end up in logs.
+static KEY: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+static BUCKETS: Lazy<Mutex<Buckets>> = Lazy::new(|| {
+	Mutex::new(Buckets {
+		map: HashMap::new(),
+		swept: Instant::now(),
+	})
+});
+
+// How often buckets that have refilled completely are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Most clients tracked at once. New clients past this are turned away until
+// a sweep frees room, so that memory stays bounded under a flood of
+// addresses.
+const MAX_BUCKETS: usize = 100_000;
+
+// When full, buckets are swept at most this often to look for room
+const FULL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// IPv6 clients usually get a whole /64, so addresses in one count as a single
+// client unless LIBREDDIT_RATE_LIMIT_IPV6_PREFIX says otherwise
+const DEFAULT_IPV6_PREFIX: u32 = 64;
+
+// Paths that are requested in bulk by every page and never hit the Reddit API
+const EXEMPT_PREFIXES: [&str; 8] = ["/vid/", "/hls/", "/img/", "/thumb/", "/emoji/", "/preview/", "/style/", "/static/"];
+const EXEMPT_PATHS: [&str; 12] = [
+	"/style.css",
+	"/manifest.json",
+	"/robots.txt",
+	"/favicon.ico",
+	"/logo.png",
+	"/Inter.var.woff2",
+	"/touch-icon-iphone.png",
+	"/apple-touch-icon.png",
+	"/playHLSVideo.js",
+	"/hls.min.js",
+	"/healthz",
+	"/readyz",
+];
+
+struct Buckets {
+	map: HashMap<u64, Bucket>,
+	swept: Instant,
+}
+
+/// A token bucket holding up to `burst` tokens, refilled at a constant rate.
+/// Each request takes one token.
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl Bucket {
+	/// Refills the bucket for the time passed since it was last used, then
+	/// takes a token. If the bucket is empty, returns how long it takes until
+	/// the next token is available.
+	fn take(&mut self, now: Instant, rate: f64, burst: f64) -> Result<(), Duration> {
+		self.tokens = (self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() * rate).min(burst);
+		self.updated = now;
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			Ok(())
+		} else {
+			Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+		}
+	}
+}
+
+/// Whether requests to `path` are exempt from rate limiting.
+pub fn exempt(path: &str) -> bool {
+	EXEMPT_PATHS.contains(&path) || EXEMPT_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+}
+
+/// Counts a request from `ip` against the limits set by LIBREDDIT_RATE_LIMIT
+/// and LIBREDDIT_RATE_LIMIT_BURST. IPv6 addresses share a limit with the rest
+/// of their LIBREDDIT_RATE_LIMIT_IPV6_PREFIX network. If the client is over
+/// the limit, returns how long it should wait before trying again.
+pub fn check(ip: IpAddr) -> Result<(), Duration> {
+	let setting = |name: &str| get_setting(name).and_then(|val| val.parse::<u32>().ok()).filter(|val| *val > 0);
+
+	let Some(per_minute) = setting("LIBREDDIT_RATE_LIMIT") else {
+		return Ok(());
+	};
+	let burst = f64::from(setting("LIBREDDIT_RATE_LIMIT_BURST").unwrap_or(per_minute));
+	let rate = f64::from(per_minute) / 60.0;
+
+	let Ok(mut buckets) = BUCKETS.lock() else {
+		return Ok(());
+	};
+	let now = Instant::now();
+
+	let prefix = get_setting("LIBREDDIT_RATE_LIMIT_IPV6_PREFIX")
+		.and_then(|val| val.parse().ok())
+		.unwrap_or(DEFAULT_IPV6_PREFIX);
+	buckets.take(KEY.hash_one(network(ip, prefix)), now, rate, burst, MAX_BUCKETS)
+}
+
+impl Buckets {
+	/// Takes a token from the bucket of the client `key`, creating it if
+	/// there is room for at most `max` buckets.
+	fn take(&mut self, key: u64, now: Instant, rate: f64, burst: f64, max: usize) -> Result<(), Duration> {
+		let full = self.map.len() >= max && !self.map.contains_key(&key);
+
+		// Forget clients whose bucket has refilled, to keep memory bounded
+		let since_sweep = now.saturating_duration_since(self.swept);
+		if since_sweep > SWEEP_INTERVAL || (full && since_sweep > FULL_SWEEP_INTERVAL) {
+			self
+				.map
+				.retain(|_, bucket| bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate < burst);
+			self.swept = now;
+		}
+
+		if self.map.len() >= max && !self.map.contains_key(&key) {
+			return Err(FULL_SWEEP_INTERVAL);
+		}
+
+		self.map.entry(key).or_insert(Bucket { tokens: burst, updated: now }).take(now, rate, burst)
+	}
+}
+
+/// The network a client is rate limited as: IPv4 addresses on their own, and
+/// IPv6 addresses with everything past the first `prefix` bits cleared.
+fn network(ip: IpAddr, prefix: u32) -> IpAddr {
+	match ip.to_canonical() {
+		IpAddr::V6(ip) => {
+			let mask = u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0);
+			IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+		}
+		ip => ip,
+	}
+}
+
+/// Counts another request against the limits of the current request's
+/// client, for handlers that make many upstream requests at once.
+pub fn charge() -> Result<(), Duration> {
+	crate::server::client_ip().map_or(Ok(()), check)
+}
+
+/// Whether `cidr` is an IP address or range in CIDR notation.
+pub fn is_cidr(cidr: &str) -> bool {
+	Cidr::parse(cidr).is_some()
+}
+
+/// Determines the IP of the client behind a connection. If the connection
+/// comes from one of the LIBREDDIT_TRUSTED_PROXIES (or over a Unix domain
+/// socket, where `peer` is `None`), the forwarding headers set by the proxy
+/// are used instead.
+pub fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap<HeaderValue>) -> Option<IpAddr> {
+	let trusted: Vec<Cidr> = get_setting("LIBREDDIT_TRUSTED_PROXIES")
+		.unwrap_or_default()
+		.split(',')
+		.filter_map(|cidr| Cidr::parse(cidr.trim()))
+		.collect();
+
+	resolve_client_ip(peer, headers, &trusted)
+}
+
+fn resolve_client_ip(peer: Option<IpAddr>, headers: &HeaderMap<HeaderValue>, trusted: &[Cidr]) -> Option<IpAddr> {
+	let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+
+	if peer.is_some_and(|ip| !is_trusted(&ip)) {
+		return peer;
+	}
+
+	// Each proxy appends the address it received the request from, so walk
+	// the chain backwards until reaching an address we don't trust. Anything
+	// further left could have been made up by the client.
+	let chain = forwarded_for(headers);
+	chain.iter().rev().find(|ip| !is_trusted(ip)).or_else(|| chain.first()).copied().or(peer)
+}
+
+/// Reads the chain of client addresses from the standard `Forwarded` header
+/// or, if absent, from `X-Forwarded-For`.
+fn forwarded_for(headers: &HeaderMap<HeaderValue>) -> Vec<IpAddr> {
+	let values = |name: &str| {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|val| val.to_str().ok())
+			.flat_map(|val| val.split(','))
+			.collect::<Vec<&str>>()
+	};
+
+	let forwarded = values("forwarded");
+	if forwarded.is_empty() {
+		values("x-forwarded-for").into_iter().filter_map(parse_node).collect()
+	} else {
+		forwarded
+			.into_iter()
+			.filter_map(|element| {
+				element
+					.split(';')
+					.filter_map(|pair| pair.split_once('='))
+					.find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+					.and_then(|(_, node)| parse_node(node))
+			})
+			.collect()
+	}
+}
+
+/// Parses a node as found in forwarding headers: an IPv4 address or a
+/// bracketed IPv6 address, both optionally followed by a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+	let node = node.trim().trim_matches('"');
+
+	if let Some(bracketed) = node.strip_prefix('[') {
+		return bracketed.split(']').next()?.parse().ok();
+	}
+
+	node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+/// An IP address range in CIDR notation, e.g. `10.0.0.0/8`. A plain address
+/// is a range containing just that address.
+struct Cidr {
+	addr: IpAddr,
+	prefix: u32,
+}
+
+impl Cidr {
+	fn parse(cidr: &str) -> Option<Self> {
+		let (addr, prefix) = cidr.split_once('/').map_or((cidr, None), |(addr, prefix)| (addr, Some(prefix)));
+		let addr: IpAddr = addr.parse().ok()?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max)?,
+			None => max,
+		};
+
+		Some(Self { addr, prefix })
+	}
+
+	fn contains(&self, ip: &IpAddr) -> bool {
+		match (self.addr, ip) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+				u32::from(net) & mask == u32::from(*ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+				u128::from(net) & mask == u128::from(*ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bucket() {
+		let start = Instant::now();
+		let mut bucket = Bucket { tokens: 2.0, updated: start };
+
+		// Burst of two, then empty
+		assert!(bucket.take(start, 1.0, 2.0).is_ok());
+		assert!(bucket.take(start, 1.0, 2.0).is_ok());
+		assert_eq!(bucket.take(start, 1.0, 2.0), Err(Duration::from_secs(1)));
+
+		// Refills over time, but never beyond the burst size
+		assert!(bucket.take(start + Duration::from_secs(1), 1.0, 2.0).is_ok());
+		assert!(bucket.take(start + Duration::from_secs(60), 1.0, 2.0).is_ok());
+		assert!(bucket.take(start + Duration::from_secs(60), 1.0, 2.0).is_ok());
+		assert!(bucket.take(start + Duration::from_secs(60), 1.0, 2.0).is_err());
+	}
+
+	#[test]
+	fn test_buckets_cap() {
+		let start = Instant::now();
+		let mut buckets = Buckets {
+			map: HashMap::new(),
+			swept: start,
+		};
+
+		assert!(buckets.take(1, start, 1.0, 2.0, 2).is_ok());
+		assert!(buckets.take(2, start, 1.0, 2.0, 2).is_ok());
+
+		// Known clients go on, new ones are turned away while full
+		assert!(buckets.take(1, start, 1.0, 2.0, 2).is_ok());
+		assert_eq!(buckets.take(3, start, 1.0, 2.0, 2), Err(FULL_SWEEP_INTERVAL));
+
+		// Once a bucket has refilled, it is swept to make room
+		let later = start + Duration::from_secs(5);
+		assert!(buckets.take(3, later, 1.0, 2.0, 2).is_ok());
+		assert_eq!(buckets.map.len(), 1);
+	}
+
+	#[test]
+	fn test_network() {
+		let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+		assert_eq!(network(ip("203.0.113.7"), 64), ip("203.0.113.7"));
+		assert_eq!(network(ip("::ffff:203.0.113.7"), 64), ip("203.0.113.7"));
+		assert_eq!(network(ip("2001:db8:1:2:3:4:5:6"), 64), ip("2001:db8:1:2::"));
+		assert_eq!(network(ip("2001:db8:1:2:3:4:5:6"), 48), ip("2001:db8:1::"));
+		assert_eq!(network(ip("2001:db8:1:2:3:4:5:6"), 128), ip("2001:db8:1:2:3:4:5:6"));
+		assert_eq!(network(ip("2001:db8:1:2:3:4:5:6"), 0), ip("::"));
+	}
+
+	#[test]
+	fn test_client_ip() {
+		let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+		let headers = |name: &'static str, val: &'static str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(name, HeaderValue::from_static(val));
+			headers
+		};
+		let trusted = [Cidr::parse("10.0.0.0/8").unwrap(), Cidr::parse("::1").unwrap()];
+
+		// Headers from untrusted peers are ignored
+		let xff = headers("x-forwarded-for", "1.1.1.1, 2.2.2.2");
+		assert_eq!(resolve_client_ip(Some(ip("3.3.3.3")), &xff, &trusted), Some(ip("3.3.3.3")));
+
+		// The closest untrusted hop is the client
+		let xff = headers("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.1.2.3");
+		assert_eq!(resolve_client_ip(Some(ip("10.0.0.1")), &xff, &trusted), Some(ip("2.2.2.2")));
+		assert_eq!(resolve_client_ip(None, &xff, &trusted), Some(ip("2.2.2.2")));
+
+		let forwarded = headers("forwarded", "for=1.1.1.1;proto=https, for=\"[2001:db8::1]:4711\"");
+		assert_eq!(resolve_client_ip(Some(ip("::1")), &forwarded, &trusted), Some(ip("2001:db8::1")));
+
+		// Without forwarding headers, fall back to the peer
+		assert_eq!(resolve_client_ip(Some(ip("10.0.0.1")), &HeaderMap::new(), &trusted), Some(ip("10.0.0.1")));
+		assert_eq!(resolve_client_ip(None, &HeaderMap::new(), &trusted), None);
+	}
+
+	#[test]
+	fn test_cidr() {
+		let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+		assert!(Cidr::parse("192.168.0.0/16").unwrap().contains(&ip("192.168.42.1")));
+		assert!(!Cidr::parse("192.168.0.0/16").unwrap().contains(&ip("192.169.0.1")));
+		assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+		assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+		assert!(!Cidr::parse("fd00::/8").unwrap().contains(&ip("127.0.0.1")));
+		assert!(Cidr::parse("10.0.0.0/33").is_none());
+		assert!(Cidr::parse("nonsense").is_none());
+	}
+}
@@ -1,6 +1,8 @@
 // CRATES
-use crate::utils::{self, catch_random, error, filter_posts, format_num, format_url, get_filters, param, redirect, setting, template, val, Post, Preferences};
+use crate::content_filter::ContentFilter;
+use crate::utils::{self, catch_random, error, filter_posts, format_num, format_url, get_filters, param, redirect, setting, template, val, ListingFilter, Post, Preferences};
 use crate::{
+	blocklist,
 	client::json,
 	subreddit::{can_access_quarantine, quarantine},
 	RequestExt,
@@ -44,6 +46,9 @@ struct SearchTemplate {
 	/// Whether all fetched posts are filtered (to differentiate between no posts fetched in the first place,
 	/// and all fetched posts being filtered).
 	all_posts_filtered: bool,
+	/// Number of fetched posts that were filtered.
+	num_posts_filtered: u64,
+	listing_filter: ListingFilter,
 	/// Whether all posts were hidden because they are NSFW (and user has disabled show NSFW)
 	all_posts_hidden_nsfw: bool,
 	no_posts: bool,
@@ -79,15 +84,24 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(random);
 	}
 
+	// Refuse searching blocked subreddits, and leave them out of multireddits
+	let path = match blocklist::allowed_subreddits(&sub) {
+		Some(allowed) if !sub.is_empty() => path.replacen(&format!("/r/{}/", sub), &format!("/r/{}/", allowed), 1),
+		Some(_) => path,
+		None => return blocklist::unavailable(req).await,
+	};
+
 	let typed = param(&path, "type").unwrap_or_default();
 
 	let sort = param(&path, "sort").unwrap_or_else(|| "relevance".to_string());
 	let filters = get_filters(&req);
+	let content_filters = ContentFilter::all(&req);
+	let listing_filter = ListingFilter::new(&req);
 
 	// If search is not restricted to this subreddit, show other subreddits in search results
 	let subreddits = if param(&path, "restrict_sr").is_none() {
 		let mut subreddits = search_subreddits(&query, &typed).await;
-		subreddits.retain(|s| !filters.contains(s.name.as_str()));
+		subreddits.retain(|s| !filters.contains(s.name.as_str()) && !blocklist::subreddit_blocked(&s.name));
 		subreddits
 	} else {
 		Vec::new()
@@ -114,13 +128,15 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 			url,
 			is_filtered: true,
 			all_posts_filtered: false,
+			num_posts_filtered: 0,
+			listing_filter,
 			all_posts_hidden_nsfw: false,
 			no_posts: false,
 		})
 	} else {
 		match Post::fetch(&path, quarantined).await {
 			Ok((mut posts, after)) => {
-				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				let (num_posts_filtered, all_posts_filtered) = filter_posts(&mut posts, &filters, &content_filters, &listing_filter);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
 				template(SearchTemplate {
@@ -140,6 +156,8 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 					url,
 					is_filtered: false,
 					all_posts_filtered,
+					num_posts_filtered,
+					listing_filter,
 					all_posts_hidden_nsfw,
 					no_posts,
 				})
@@ -2,28 +2,60 @@ use brotli::enc::{BrotliCompress, BrotliEncoderParams};
 use cached::proc_macro::cached;
 use cookie::Cookie;
 use core::f64;
-use futures_lite::{future::Boxed, Future, FutureExt};
+use futures_lite::{future::Boxed, stream, Future, FutureExt};
 use hyper::{
 	body,
 	body::HttpBody,
 	header,
+	http::uri::Authority,
+	server::{
+		accept::{self, Accept},
+		conn::{AddrIncoming, AddrStream},
+	},
 	service::{make_service_fn, service_fn},
 	HeaderMap,
 };
-use hyper::{Body, Method, Request, Response, Server as HyperServer};
+use hyper::{Body, Method, Request, Response, Server as HyperServer, Uri};
 use libflate::gzip;
+use listenfd::ListenFd;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use route_recognizer::{Params, Router};
 use std::{
+	any::Any,
+	cell::RefCell,
 	cmp::Ordering,
+	collections::hash_map::DefaultHasher,
+	hash::{Hash, Hasher},
 	io,
+	net::{IpAddr, SocketAddr, TcpListener as StdTcpListener},
+	panic::AssertUnwindSafe,
 	pin::Pin,
 	result::Result,
 	str::{from_utf8, Split},
 	string::ToString,
+	time::{Duration as StdDuration, Instant},
 };
 use time::Duration;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::{TcpListener, TcpStream},
+	sync::watch,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
-use crate::dbg_msg;
+#[cfg(unix)]
+use std::{
+	fs,
+	os::unix::{
+		fs::{FileTypeExt, PermissionsExt},
+		net::UnixListener,
+	},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::{access_log::AccessLog, dbg_msg, ratelimit, tls};
 
 type BoxResponse = Pin<Box<dyn Future<Output = Result<Response<Body>, String>> + Send>>;
 
@@ -76,13 +108,181 @@ impl ToString for CompressionType {
 }
 
 pub struct Route<'a> {
-	router: &'a mut Router<fn(Request<Body>) -> BoxResponse>,
+	router: &'a mut Router<Endpoint>,
 	path: String,
 }
 
+/// A route's handler, along with the pattern it was registered with (e.g.
+/// `/r/:sub`), which identifies the route in logs.
+#[derive(Clone)]
+struct Endpoint {
+	pattern: String,
+	handler: fn(Request<Body>) -> BoxResponse,
+}
+
 pub struct Server {
 	pub default_headers: HeaderMap,
-	router: Router<fn(Request<Body>) -> BoxResponse>,
+	/// Headers added after the default headers that are derived from the
+	/// configuration, so they follow it when it is reloaded.
+	pub config_headers: fn() -> HeaderMap,
+	/// Permissions of the socket file when listening on a Unix domain socket.
+	pub socket_mode: Option<u32>,
+	/// Serve HTTPS instead of plain HTTP on TCP sockets.
+	pub tls: Option<TlsAcceptor>,
+	/// Address of a companion plain HTTP listener redirecting to HTTPS.
+	pub redirect_http: Option<String>,
+	/// How long in-flight requests may take to finish once shutdown begins.
+	pub shutdown_timeout: StdDuration,
+	/// Path prefix the app is served under (e.g. `/reddit`), or empty if
+	/// served at the root. It is stripped from incoming requests and added to
+	/// the links, redirects and cookies of outgoing responses.
+	pub base_path: String,
+	/// Writes a line to standard output for every request, if set.
+	pub access_log: Option<AccessLog>,
+	/// Renders the page for error conditions detected by the server itself,
+	/// such as a client being rate limited.
+	pub error_page: fn(Request<Body>, u16, String) -> BoxResponse,
+	/// Derives the part of a page's ETag that depends on the request itself
+	/// (as opposed to the upstream payloads the page was built from). Only
+	/// called for [`Conditional`] responses, with a body-less copy of the request.
+	pub etag_seed: fn(&Request<Body>) -> u64,
+	/// Prepares a request before its route's handler runs, e.g. to look up
+	/// once what the handler would otherwise look up repeatedly.
+	pub prepare: fn(&mut Request<Body>),
+	router: Router<Endpoint>,
+}
+
+/// Marks a response as eligible for conditional requests. The server will
+/// attach a weak ETag to it and answer a matching `If-None-Match` with a
+/// `304 Not Modified`.
+#[derive(Clone, Copy, Debug)]
+pub struct Conditional;
+
+// Flipped to `true` once the server is asked to shut down
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Whether the server is draining in-flight requests before shutting down.
+pub fn draining() -> bool {
+	*SHUTDOWN.borrow()
+}
+
+/// Resolves once the server starts shutting down.
+pub async fn shutdown_started() {
+	let _ = SHUTDOWN.subscribe().wait_for(|draining| *draining).await;
+}
+
+/// Waits for SIGTERM or SIGINT (CTRL+C) and starts shutting down.
+async fn wait_for_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+
+		let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
+		let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT signal handler");
+		tokio::select! {
+			_ = sigterm.recv() => {},
+			_ = sigint.recv() => {},
+		}
+	}
+
+	#[cfg(not(unix))]
+	tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
+
+	println!("Shutting down, waiting for in-flight requests to finish...");
+	SHUTDOWN.send_replace(true);
+}
+
+/// What happened upstream while handling the current request.
+#[derive(Default)]
+struct Upstream {
+	// Digests of the upstream payloads consumed
+	digests: Vec<u64>,
+	// Whether an upstream request was refused for lack of capacity
+	throttled: bool,
+	// Number of requests made upstream
+	calls: u32,
+	// Size of the upstream response bodies, where known
+	bytes: u64,
+}
+
+tokio::task_local! {
+	static UPSTREAM: RefCell<Upstream>;
+	static NONCE: String;
+	static CLIENT_IP: Option<IpAddr>;
+}
+
+/// The nonce of the current request, for templates to put on inline
+/// `<style>` elements so that the Content-Security-Policy allows them.
+pub fn nonce() -> String {
+	NONCE.try_with(String::clone).unwrap_or_default()
+}
+
+/// The IP address of the client of the current request, if known.
+pub fn client_ip() -> Option<IpAddr> {
+	CLIENT_IP.try_with(|ip| *ip).ok().flatten()
+}
+
+/// Generates a random nonce and fills it into the `{nonce}` placeholders of
+/// Content-Security-Policy headers.
+fn with_nonce(mut headers: HeaderMap<header::HeaderValue>) -> (String, HeaderMap<header::HeaderValue>) {
+	let nonce = format!("{:032x}", rand::random::<u128>());
+
+	for name in [header::CONTENT_SECURITY_POLICY, header::CONTENT_SECURITY_POLICY_REPORT_ONLY] {
+		if let Some(policy) = headers.get(&name).and_then(|val| val.to_str().ok()).filter(|val| val.contains("{nonce}")) {
+			if let Ok(val) = header::HeaderValue::from_str(&policy.replace("{nonce}", &nonce)) {
+				headers.insert(name, val);
+			}
+		}
+	}
+
+	(nonce, headers)
+}
+
+/// Records the digest of an upstream payload the current response is built
+/// from. This is a no-op outside of a request handled by `Server`.
+pub fn note_upstream(digest: u64) {
+	let _ = UPSTREAM.try_with(|upstream| upstream.borrow_mut().digests.push(digest));
+}
+
+/// Records a request made upstream and the size of its response body. This
+/// is a no-op outside of a request handled by `Server`.
+pub fn note_upstream_call(bytes: u64) {
+	let _ = UPSTREAM.try_with(|upstream| {
+		let mut upstream = upstream.borrow_mut();
+		upstream.calls += 1;
+		upstream.bytes += bytes;
+	});
+}
+
+/// Records that an upstream request had to be given up because too many were
+/// in flight. The server then answers with a `429 Too Many Requests`.
+pub fn note_throttled() {
+	let _ = UPSTREAM.try_with(|upstream| upstream.borrow_mut().throttled = true);
+}
+
+/// Connections that know the IP address of their peer.
+pub trait PeerAddr {
+	/// Returns `None` for Unix domain sockets, whose peers have no IP.
+	fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+impl PeerAddr for AddrStream {
+	fn peer_ip(&self) -> Option<IpAddr> {
+		Some(self.remote_addr().ip())
+	}
+}
+
+impl PeerAddr for TlsStream<TcpStream> {
+	fn peer_ip(&self) -> Option<IpAddr> {
+		self.get_ref().0.peer_addr().ok().map(|addr| addr.ip())
+	}
+}
+
+#[cfg(unix)]
+impl PeerAddr for UnixStream {
+	fn peer_ip(&self) -> Option<IpAddr> {
+		None
+	}
 }
 
 #[macro_export]
@@ -131,19 +331,32 @@ impl RequestExt for Request<Body> {
 		self.extensions_mut().insert(params)
 	}
 
+	// HTTP/2 clients may send each cookie in a header of its own, which
+	// together mean the same as one header joining them with "; "
 	fn cookies(&self) -> Vec<Cookie> {
-		self.headers().get("Cookie").map_or(Vec::new(), |header| {
-			header
-				.to_str()
-				.unwrap_or_default()
-				.split("; ")
-				.map(|cookie| Cookie::parse(cookie).unwrap_or_else(|_| Cookie::named("")))
-				.collect()
-		})
+		self
+			.headers()
+			.get_all("Cookie")
+			.iter()
+			.flat_map(|header| header.to_str().unwrap_or_default().split("; "))
+			.map(|cookie| Cookie::parse(cookie).unwrap_or_else(|_| Cookie::named("")))
+			.collect()
 	}
 
 	fn cookie(&self, name: &str) -> Option<Cookie> {
-		self.cookies().into_iter().find(|c| c.name() == name)
+		let cookies = self.cookies();
+		if let Some(cookie) = cookies.iter().find(|c| c.name() == name) {
+			return Some(cookie.clone());
+		}
+
+		// Rejoin values that were split across numbered cookies
+		if !SHARDED_COOKIES.contains(&name) {
+			return None;
+		}
+		let shards: Vec<&str> = (1..)
+			.map_while(|i| cookies.iter().find(|c| c.name() == format!("{}{}", name, i)).map(Cookie::value))
+			.collect();
+		(!shards.is_empty()).then(|| Cookie::new(name.to_string(), shards.concat()))
 	}
 }
 
@@ -160,24 +373,106 @@ impl ResponseExt for Response<Body> {
 	}
 
 	fn insert_cookie(&mut self, cookie: Cookie) {
-		if let Ok(val) = header::HeaderValue::from_str(&cookie.to_string()) {
-			self.headers_mut().append("Set-Cookie", val);
+		if SHARDED_COOKIES.contains(&cookie.name()) {
+			let name = cookie.name().to_string();
+			let shards = shard(cookie.value());
+
+			// A value that fits in a single cookie replaces any shards, while one
+			// that does not replaces the single cookie. Shards past the last one
+			// are removed so that none of a longer earlier value is left behind.
+			if shards.len() == 1 {
+				expire_shards(self, &name, 1);
+			} else {
+				for (i, value) in shards.iter().enumerate() {
+					let mut shard = cookie.clone();
+					shard.set_name(format!("{}{}", name, i + 1));
+					shard.set_value(value.to_string());
+					append_cookie(self, &shard);
+				}
+				expire_shards(self, &name, shards.len() + 1);
+				expire_cookie(self, name);
+				return;
+			}
 		}
+
+		append_cookie(self, &cookie);
 	}
 
 	fn remove_cookie(&mut self, name: String) {
-		let mut cookie = Cookie::named(name);
-		cookie.set_path("/");
-		cookie.set_max_age(Duration::seconds(1));
-		if let Ok(val) = header::HeaderValue::from_str(&cookie.to_string()) {
-			self.headers_mut().append("Set-Cookie", val);
+		if SHARDED_COOKIES.contains(&name.as_str()) {
+			expire_shards(self, &name, 1);
+		}
+		expire_cookie(self, name);
+	}
+}
+
+/// Cookies whose values can outgrow the roughly 4 KB browsers allow for a
+/// single cookie. Their values are split across numbered cookies
+/// (`subscriptions1`, `subscriptions2`, …) that [`RequestExt::cookie`] joins
+/// back together.
+pub const SHARDED_COOKIES: [&str; 8] = [
+	"subscriptions",
+	"filters",
+	"feeds",
+	"content_filters",
+	"hidden_posts",
+	"read_posts",
+	"bookmarks",
+	"sub_preferences",
+];
+
+// Longest value of a single shard, leaving room for the name and attributes
+const SHARD_SIZE: usize = 3500;
+
+// Shards up to this index are removed when a value shrinks. Browsers and
+// proxies refuse requests carrying this many long before it is reached.
+const MAX_SHARDS: usize = 12;
+
+/// Splits a cookie value into shards of at most [`SHARD_SIZE`] bytes.
+fn shard(value: &str) -> Vec<&str> {
+	let mut shards = Vec::new();
+	let mut rest = value;
+	while rest.len() > SHARD_SIZE {
+		let mut end = SHARD_SIZE;
+		while !rest.is_char_boundary(end) {
+			end -= 1;
 		}
+		let (shard, remainder) = rest.split_at(end);
+		shards.push(shard);
+		rest = remainder;
+	}
+	shards.push(rest);
+	shards
+}
+
+fn append_cookie(res: &mut Response<Body>, cookie: &Cookie) {
+	if let Ok(val) = header::HeaderValue::from_str(&cookie.to_string()) {
+		res.headers_mut().append("Set-Cookie", val);
 	}
 }
 
+/// Expires the shards of `name` from index `from` up to [`MAX_SHARDS`], or
+/// just the one at `from` if that is already past it.
+fn expire_shards(res: &mut Response<Body>, name: &str, from: usize) {
+	for i in from..=MAX_SHARDS.max(from) {
+		expire_cookie(res, format!("{}{}", name, i));
+	}
+}
+
+fn expire_cookie(res: &mut Response<Body>, name: String) {
+	let mut cookie = Cookie::named(name);
+	cookie.set_path("/");
+	cookie.set_max_age(Duration::seconds(1));
+	append_cookie(res, &cookie);
+}
+
 impl Route<'_> {
 	fn method(&mut self, method: Method, dest: fn(Request<Body>) -> BoxResponse) -> &mut Self {
-		self.router.add(&format!("/{}{}", method.as_str(), self.path), dest);
+		let endpoint = Endpoint {
+			pattern: self.path.clone(),
+			handler: dest,
+		};
+		self.router.add(&format!("/{}{}", method.as_str(), self.path), endpoint);
 		self
 	}
 
@@ -196,6 +491,16 @@ impl Server {
 	pub fn new() -> Self {
 		Server {
 			default_headers: HeaderMap::new(),
+			config_headers: HeaderMap::new,
+			socket_mode: None,
+			tls: None,
+			redirect_http: None,
+			shutdown_timeout: StdDuration::from_secs(10),
+			base_path: String::new(),
+			access_log: None,
+			error_page: |_, status, msg| new_boilerplate(HeaderMap::new(), HeaderMap::new(), status, msg.into()).boxed(),
+			etag_seed: |_| 0,
+			prepare: |_| (),
 			router: Router::new(),
 		}
 	}
@@ -208,19 +513,90 @@ impl Server {
 	}
 
 	pub fn listen(self, addr: String) -> Boxed<Result<(), hyper::Error>> {
-		let make_svc = make_service_fn(move |_conn| {
+		// A listening socket passed by systemd (socket activation) takes
+		// precedence over the configured address. This way, the socket stays
+		// open across restarts and no connection is dropped.
+		let mut listenfd = ListenFd::from_env();
+
+		#[cfg(unix)]
+		if let Ok(Some(listener)) = listenfd.take_unix_listener(0) {
+			println!("Using Unix domain socket passed by systemd");
+			return self.serve(unix_incoming(listener));
+		}
+
+		let listener = if let Ok(Some(listener)) = listenfd.take_tcp_listener(0) {
+			println!("Using TCP socket passed by systemd");
+			listener
+		} else {
+			// Listen on a Unix domain socket if the address is given as unix:PATH
+			#[cfg(unix)]
+			if let Some(path) = addr.strip_prefix("unix:") {
+				let listener = bind_unix(path, self.socket_mode).unwrap_or_else(|e| panic!("Cannot listen on Unix domain socket {}: {}", path, e));
+				return self.serve(unix_incoming(listener));
+			}
+
+			// Build SocketAddr from provided address
+			let address: SocketAddr = addr.parse().unwrap_or_else(|_| panic!("Cannot parse {} as address (example format: 0.0.0.0:8080)", addr));
+			StdTcpListener::bind(address).unwrap_or_else(|e| panic!("Cannot bind to {}: {}", addr, e))
+		};
+
+		let listener = listener
+			.set_nonblocking(true)
+			.and_then(|_| TcpListener::from_std(listener))
+			.unwrap_or_else(|e| panic!("Cannot listen on {}: {}", addr, e));
+
+		match self.tls.clone() {
+			Some(acceptor) => {
+				// Redirect plain HTTP requests to the HTTPS listener
+				if let (Some(redirect_addr), Ok(local_addr)) = (&self.redirect_http, listener.local_addr()) {
+					tokio::spawn(redirect_to_https(redirect_addr.to_owned(), local_addr.port()));
+				}
+
+				self.serve(tls::incoming(listener, acceptor))
+			}
+			None => {
+				let incoming = AddrIncoming::from_listener(listener).unwrap_or_else(|e| panic!("Cannot listen on {}: {}", addr, e));
+				self.serve(incoming)
+			}
+		}
+	}
+
+	/// Serves the routes of this server to every connection accepted by
+	/// `incoming`, until CTRL+C is pressed.
+	fn serve<I>(self, incoming: I) -> Boxed<Result<(), hyper::Error>>
+	where
+		I: Accept + Send + 'static,
+		I::Conn: AsyncRead + AsyncWrite + PeerAddr + Unpin + Send + 'static,
+		I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		let make_svc = make_service_fn(move |conn: &I::Conn| {
 			// For correct borrowing, these values need to be borrowed
 			let router = self.router.clone();
 			let default_headers = self.default_headers.clone();
+			let config_headers = self.config_headers;
+			let etag_seed = self.etag_seed;
+			let prepare = self.prepare;
+			let error_page = self.error_page;
+			let base_path = self.base_path.clone();
+			let access_log = self.access_log.clone();
+			let peer = conn.peer_ip();
 
 			// This is the `Service` that will handle the connection.
 			// `service_fn` is a helper to convert a function that
 			// returns a Response into a `Service`.
 			// let shared_router = router.clone();
 			async move {
-				Ok::<_, String>(service_fn(move |req: Request<Body>| {
+				Ok::<_, String>(service_fn(move |mut req: Request<Body>| {
+					let start = Instant::now();
 					let req_headers = req.headers().clone();
-					let def_headers = default_headers.clone();
+					let mut def_headers = default_headers.clone();
+					def_headers.extend(config_headers());
+					let (nonce, def_headers) = with_nonce(def_headers);
+					let base_path = base_path.clone();
+					let client_ip = ratelimit::client_ip(peer, &req_headers);
+
+					// Requests outside of the base path don't belong to us
+					let in_base_path = strip_base_path(&mut req, &base_path);
 
 					// Remove double slashes and decode encoded slashes
 					let mut path = req.uri().path().replace("//", "/").replace("%2F", "/");
@@ -231,18 +607,71 @@ impl Server {
 					}
 
 					// Match the visited path with an added route
-					match router.recognize(&format!("/{}{}", req.method().as_str(), path)) {
+					let found = if in_base_path {
+						router.recognize(&format!("/{}{}", req.method().as_str(), path))
+					} else {
+						Err("Not found".to_string())
+					};
+					let pattern = found.as_ref().ok().map(|found| found.handler().pattern.clone());
+					let log_entry = access_log.as_ref().map(|log| log.start(req.method(), pattern.as_deref(), client_ip, req.uri().query()));
+
+					// Throttle clients making too many requests
+					let throttled = client_ip.filter(|_| !ratelimit::exempt(&path)).and_then(|ip| ratelimit::check(ip).err());
+
+					let response = match found {
+						_ if throttled.is_some() => {
+							let page = error_page(req, 429, "Too many requests, please slow down".to_string());
+							too_many_requests(page, throttled, def_headers, req_headers, base_path)
+						}
 						// If a route was configured for this path
 						Ok(found) => {
+							let uri = req.uri().clone();
 							let mut parammed = req;
 							parammed.set_params(found.params().clone());
+							prepare(&mut parammed);
 
-							// Run the route's function
-							let func = (found.handler().to_owned().to_owned())(parammed);
+							// Run the route's function, turning panics into error pages
+							let route = found.handler().pattern.clone();
+							let func = (found.handler().handler)(parammed);
 							async move {
-								match func.await {
+								let result = match AssertUnwindSafe(func).catch_unwind().await {
+									Ok(result) => result,
+									Err(panic) => {
+										eprintln!("Handler for {} panicked: {}", route, panic_message(&*panic));
+										let req = rebuild_request(uri.clone(), &req_headers);
+										error_page(req, 500, "Something went wrong while loading this page".to_string()).await
+									}
+								};
+								let (digests, throttled) = UPSTREAM.with(|upstream| {
+									let upstream = upstream.borrow();
+									(upstream.digests.clone(), upstream.throttled)
+								});
+
+								// If the page couldn't be built because Reddit is busy, ask the client to retry
+								if throttled && !result.as_ref().is_ok_and(|res| res.status().is_success()) {
+									let req = rebuild_request(uri.clone(), &req_headers);
+									let page = error_page(req, 429, "Too many requests, please try again later".to_string());
+									return too_many_requests(page, None, def_headers, req_headers, base_path).await;
+								}
+
+								match result {
 									Ok(mut res) => {
-										res.headers_mut().extend(def_headers);
+										res.headers_mut().extend(def_headers.clone());
+										add_base_path(&mut res, &base_path).await;
+
+										// Answer conditional requests before spending time on compression.
+										// Only these pay for the seed, which builds the user's preferences.
+										if res.extensions().get::<Conditional>().is_some() {
+											let seed = etag_seed(&rebuild_request(uri, &req_headers));
+											let etag = weak_etag(seed, &digests);
+											if etag_matches(&req_headers, &etag) {
+												return not_modified(def_headers, &etag);
+											}
+											if let Ok(val) = header::HeaderValue::from_str(&etag) {
+												res.headers_mut().insert(header::ETAG, val);
+											}
+										}
+
 										let _ = compress_response(&req_headers, &mut res).await;
 
 										Ok(res)
@@ -254,21 +683,431 @@ impl Server {
 						}
 						// If there was a routing error
 						Err(e) => new_boilerplate(def_headers, req_headers, 404, e.into()).boxed(),
-					}
+					};
+
+					let response = CLIENT_IP.scope(client_ip, NONCE.scope(nonce, response));
+					UPSTREAM
+						.scope(RefCell::default(), async move {
+							let res = response.await;
+
+							if let Some(entry) = log_entry {
+								let status = res.as_ref().map_or(500, |res| res.status().as_u16());
+								let (calls, bytes) = UPSTREAM.with(|upstream| {
+									let upstream = upstream.borrow();
+									(upstream.calls, upstream.bytes)
+								});
+								entry.finish(status, start.elapsed(), calls, bytes);
+							}
+
+							res
+						})
+						.boxed()
 				}))
 			}
 		});
 
-		// Build SocketAddr from provided address
-		let address = &addr.parse().unwrap_or_else(|_| panic!("Cannot parse {} as address (example format: 0.0.0.0:8080)", addr));
+		tokio::spawn(wait_for_signal());
+
+		// Gracefully shut down on SIGTERM or SIGINT: stop accepting connections
+		// and let in-flight requests finish, but only up to the timeout.
+		let server = HyperServer::builder(incoming).serve(make_svc).with_graceful_shutdown(shutdown_started());
+		let shutdown_timeout = self.shutdown_timeout;
+		let deadline = async move {
+			shutdown_started().await;
+			tokio::time::sleep(shutdown_timeout).await;
+			eprintln!("Shutdown timeout of {}s reached, dropping remaining connections", shutdown_timeout.as_secs());
+			Ok(())
+		};
+
+		server.or(deadline).boxed()
+	}
+}
 
-		// Bind server to address specified above. Gracefully shut down if CTRL+C is pressed
-		let server = HyperServer::bind(address).serve(make_svc).with_graceful_shutdown(async {
-			// Wait for the CTRL+C signal
-			tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
-		});
+/// Listens for plain HTTP requests on `addr` and redirects them to the HTTPS
+/// listener on `https_port`.
+async fn redirect_to_https(addr: String, https_port: u16) {
+	let address: SocketAddr = addr.parse().unwrap_or_else(|_| panic!("Cannot parse {} as address (example format: 0.0.0.0:80)", addr));
 
-		server.boxed()
+	let make_svc =
+		make_service_fn(move |_conn| async move { Ok::<_, String>(service_fn(move |req: Request<Body>| async move { Ok::<_, String>(https_redirect(&req, https_port)) })) });
+
+	let server = HyperServer::try_bind(&address)
+		.unwrap_or_else(|e| panic!("Cannot bind to {}: {}", addr, e))
+		.serve(make_svc)
+		.with_graceful_shutdown(shutdown_started());
+
+	println!("Redirecting HTTP requests on {} to HTTPS", addr);
+
+	if let Err(e) = server.await {
+		eprintln!("HTTP redirect server error: {}", e);
+	}
+}
+
+/// Create a permanent redirect to the HTTPS version of the requested URL. The
+/// host is taken from the `Host` header, as plain HTTP requests don't carry
+/// it in their URI.
+fn https_redirect(req: &Request<Body>, https_port: u16) -> Response<Body> {
+	let host = req
+		.headers()
+		.get(header::HOST)
+		.and_then(|hdr| hdr.to_str().ok())
+		.and_then(|host| host.parse::<Authority>().ok())
+		.map(|authority| authority.host().to_owned());
+
+	let Some(host) = host else {
+		return Response::builder().status(400).body("Missing Host header".into()).unwrap_or_default();
+	};
+
+	let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+	let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+	Response::builder()
+		.status(301)
+		.header(header::LOCATION, format!("https://{}{}{}", host, port, path))
+		.body(Body::empty())
+		.unwrap_or_default()
+}
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket left behind
+/// by a previous run. If `mode` is given, the socket's permissions are set
+/// accordingly (e.g. `0o660` to let a reverse proxy in the same group
+/// connect).
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<UnixListener> {
+	if fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or_default() {
+		fs::remove_file(path)?;
+	}
+
+	let listener = UnixListener::bind(path)?;
+	if let Some(mode) = mode {
+		fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+	}
+
+	Ok(listener)
+}
+
+/// Accepts connections on a Unix domain socket.
+#[cfg(unix)]
+fn unix_incoming(listener: UnixListener) -> impl Accept<Conn = tokio::net::UnixStream, Error = io::Error> {
+	let listener = listener
+		.set_nonblocking(true)
+		.and_then(|_| tokio::net::UnixListener::from_std(listener))
+		.unwrap_or_else(|e| panic!("Cannot listen on Unix domain socket: {}", e));
+
+	accept::from_stream(stream::unfold(listener, |listener| async move {
+		let conn = listener.accept().await.map(|(stream, _)| stream);
+		Some((conn, listener))
+	}))
+}
+
+/// Creates a body-less copy of a request that has been handed to a handler
+/// already, so that an error page can be rendered for it.
+fn rebuild_request(uri: Uri, headers: &HeaderMap) -> Request<Body> {
+	let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap_or_default();
+	*req.headers_mut() = headers.clone();
+	req
+}
+
+/// Extracts the message from the payload of a panic.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+	panic
+		.downcast_ref::<&str>()
+		.copied()
+		.or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+		.unwrap_or("unknown cause")
+}
+
+/// Finishes a `429 Too Many Requests` page, telling the client when to retry
+/// if known.
+fn too_many_requests(page: BoxResponse, retry_after: Option<StdDuration>, default_headers: HeaderMap, req_headers: HeaderMap, base_path: String) -> BoxResponse {
+	async move {
+		let mut res = page.await?;
+		*res.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
+		res.headers_mut().extend(default_headers);
+		add_base_path(&mut res, &base_path).await;
+
+		if let Some(wait) = retry_after {
+			// Round up, as retrying early would be refused again
+			res.headers_mut().insert(header::RETRY_AFTER, (wait.as_secs() + 1).into());
+		}
+
+		let _ = compress_response(&req_headers, &mut res).await;
+
+		Ok(res)
+	}
+	.boxed()
+}
+
+/// Removes `base_path` from the URI of `req`, so that routes and handlers
+/// never see it. Returns `false` if the request lies outside of `base_path`.
+fn strip_base_path(req: &mut Request<Body>, base_path: &str) -> bool {
+	if base_path.is_empty() {
+		return true;
+	}
+
+	let Some(rest) = req.uri().path().strip_prefix(base_path) else {
+		return false;
+	};
+	if !rest.is_empty() && !rest.starts_with('/') {
+		return false;
+	}
+
+	let path_and_query = match req.uri().query() {
+		Some(query) => format!("{}?{}", if rest.is_empty() { "/" } else { rest }, query),
+		None => if rest.is_empty() { "/" } else { rest }.to_string(),
+	};
+
+	match Uri::builder().path_and_query(path_and_query).build() {
+		Ok(uri) => {
+			*req.uri_mut() = uri;
+			true
+		}
+		Err(_) => false,
+	}
+}
+
+// Matches root-relative URLs in CSS `url()`s, but not protocol-relative ones
+// (`//example.com`).
+static CSS_URL: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(url\(["']?)/([^/])"#).unwrap());
+
+// Attributes holding a link
+const LINK_ATTRIBUTES: [&str; 4] = ["href", "src", "action", "poster"];
+
+/// Prefixes root-relative URLs in rendered HTML with `base_path`, as
+/// templates and handlers build links assuming the app lives at `/`. Only
+/// the values of link attributes and the `url()`s of `style` attributes and
+/// `<style>` elements are rebased; text, other attributes and `<script>`
+/// bodies are left alone.
+pub fn rebase_urls(html: &str, base_path: &str) -> String {
+	let mut rebased = String::with_capacity(html.len());
+	let mut rest = html;
+
+	while let Some(start) = rest.find('<') {
+		rebased.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		// A `<` that doesn't start a tag is text
+		let Some(end) = tag_end(rest) else {
+			rebased.push('<');
+			rest = &rest[1..];
+			continue;
+		};
+		let tag = &rest[..end];
+		rebased.push_str(&rebase_tag(tag, base_path));
+		rest = &rest[end..];
+
+		// The bodies of these elements are raw text rather than HTML, up to
+		// their closing tag
+		let name = tag[1..]
+			.split(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+			.next()
+			.unwrap_or_default()
+			.to_ascii_lowercase();
+		if name == "script" || name == "style" {
+			let body_end = rest.to_ascii_lowercase().find(&format!("</{}", name)).unwrap_or(rest.len());
+			let body = &rest[..body_end];
+			if name == "style" {
+				rebased.push_str(&rebase_css_urls(body, base_path));
+			} else {
+				rebased.push_str(body);
+			}
+			rest = &rest[body_end..];
+		}
+	}
+
+	rebased.push_str(rest);
+	rebased
+}
+
+/// Returns the length of the tag `html` starts with, if it starts with one.
+/// A `>` inside a quoted attribute value doesn't end the tag.
+fn tag_end(html: &str) -> Option<usize> {
+	let bytes = html.as_bytes();
+	if !bytes.get(1).is_some_and(u8::is_ascii_alphabetic) {
+		return None;
+	}
+
+	let mut quote = None;
+	let mut after_equals = false;
+	for (i, &byte) in bytes.iter().enumerate().skip(1) {
+		match quote {
+			Some(open) if byte == open => quote = None,
+			Some(_) => {}
+			None if byte == b'>' => return Some(i + 1),
+			None if after_equals && (byte == b'"' || byte == b'\'') => quote = Some(byte),
+			None => {}
+		}
+		after_equals = byte == b'=' || (after_equals && byte.is_ascii_whitespace());
+	}
+	None
+}
+
+/// Prefixes the root-relative URLs in the attributes of a single tag.
+fn rebase_tag(tag: &str, base_path: &str) -> String {
+	let bytes = tag.as_bytes();
+	let mut rebased = String::with_capacity(tag.len());
+	let mut copied = 0;
+
+	// Skip the tag name
+	let mut i = 1;
+	while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' && bytes[i] != b'/' {
+		i += 1;
+	}
+
+	loop {
+		while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+			i += 1;
+		}
+		if i >= bytes.len() || bytes[i] == b'>' {
+			break;
+		}
+
+		let name_start = i;
+		while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !matches!(bytes[i], b'=' | b'>' | b'/') {
+			i += 1;
+		}
+		let name = tag[name_start..i].to_ascii_lowercase();
+
+		let mut j = i;
+		while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+			j += 1;
+		}
+		// An attribute without a value
+		if bytes.get(j) != Some(&b'=') {
+			continue;
+		}
+		j += 1;
+		while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+			j += 1;
+		}
+
+		let (value_start, value_end, next) = match bytes.get(j) {
+			Some(&quote @ (b'"' | b'\'')) => {
+				let end = tag[j + 1..].find(quote as char).map_or(bytes.len(), |end| j + 1 + end);
+				(j + 1, end, (end + 1).min(bytes.len()))
+			}
+			_ => {
+				let mut end = j;
+				while end < bytes.len() && !bytes[end].is_ascii_whitespace() && bytes[end] != b'>' {
+					end += 1;
+				}
+				(j, end, end)
+			}
+		};
+		let value = &tag[value_start..value_end];
+
+		let new_value = if LINK_ATTRIBUTES.contains(&name.as_str()) && value.starts_with('/') && !value.starts_with("//") {
+			Some(format!("{}{}", base_path, value))
+		} else if name == "style" {
+			Some(rebase_css_urls(value, base_path))
+		} else {
+			None
+		};
+		if let Some(new_value) = new_value {
+			rebased.push_str(&tag[copied..value_start]);
+			rebased.push_str(&new_value);
+			copied = value_end;
+		}
+		i = next;
+	}
+
+	rebased.push_str(&tag[copied..]);
+	rebased
+}
+
+/// Prefixes root-relative URLs in a stylesheet with `base_path`.
+pub fn rebase_css_urls(css: &str, base_path: &str) -> String {
+	CSS_URL.replace_all(css, format!("${{1}}{}/${{2}}", base_path)).into_owned()
+}
+
+/// Adds `base_path` to the redirect target, the cookie paths and the links
+/// in the HTML body of a response.
+async fn add_base_path(res: &mut Response<Body>, base_path: &str) {
+	if base_path.is_empty() {
+		return;
+	}
+
+	let headers = res.headers_mut();
+
+	if let Some(location) = headers.get(header::LOCATION).and_then(|hdr| hdr.to_str().ok()) {
+		if location.starts_with('/') && !location.starts_with("//") {
+			if let Ok(val) = header::HeaderValue::from_str(&format!("{}{}", base_path, location)) {
+				headers.insert(header::LOCATION, val);
+			}
+		}
+	}
+
+	let cookies: Vec<header::HeaderValue> = headers
+		.get_all(header::SET_COOKIE)
+		.iter()
+		.map(|hdr| {
+			let rebased = hdr.to_str().ok().and_then(|val| Cookie::parse(val).ok()).and_then(|mut cookie| {
+				let path = match cookie.path() {
+					None | Some("/") => base_path.to_string(),
+					Some(path) => format!("{}{}", base_path, path),
+				};
+				cookie.set_path(path);
+				header::HeaderValue::from_str(&cookie.to_string()).ok()
+			});
+			rebased.unwrap_or_else(|| hdr.clone())
+		})
+		.collect();
+	if !cookies.is_empty() {
+		headers.remove(header::SET_COOKIE);
+		for cookie in cookies {
+			headers.append(header::SET_COOKIE, cookie);
+		}
+	}
+
+	let is_html = headers
+		.get(header::CONTENT_TYPE)
+		.and_then(|hdr| hdr.to_str().ok())
+		.is_some_and(|val| val.starts_with("text/html"));
+	if is_html {
+		if let Ok(body) = body::to_bytes(res.body_mut()).await {
+			let html = rebase_urls(&String::from_utf8_lossy(&body), base_path);
+			res.headers_mut().remove(header::CONTENT_LENGTH);
+			*res.body_mut() = Body::from(html);
+		}
+	}
+}
+
+/// Builds a weak entity tag from the request-specific `seed` and the digests
+/// of the upstream payloads a response was built from.
+fn weak_etag(seed: u64, upstream: &[u64]) -> String {
+	let mut hasher = DefaultHasher::new();
+	seed.hash(&mut hasher);
+	upstream.hash(&mut hasher);
+	format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Determines whether the `If-None-Match` request header matches `etag`. Per
+/// [RFC 7232](https://datatracker.ietf.org/doc/html/rfc7232#section-3.2),
+/// entity tags are compared using the weak comparison function, meaning the
+/// `W/` prefix is ignored on both sides.
+fn etag_matches(req_headers: &HeaderMap<header::HeaderValue>, etag: &str) -> bool {
+	let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+	req_headers
+		.get(header::IF_NONE_MATCH)
+		.and_then(|hdr| hdr.to_str().ok())
+		.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag)))
+}
+
+/// Create a `304 Not Modified` Response for a client whose cached copy
+/// carries the given entity tag.
+fn not_modified(mut default_headers: HeaderMap<header::HeaderValue>, etag: &str) -> Result<Response<Body>, String> {
+	// The cached page carries the nonce of the response it came with, which a
+	// policy with a fresh nonce would no longer match
+	default_headers.remove(header::CONTENT_SECURITY_POLICY);
+	default_headers.remove(header::CONTENT_SECURITY_POLICY_REPORT_ONLY);
+
+	match Response::builder().status(304).header(header::ETAG, etag).body(Body::empty()) {
+		Ok(mut res) => {
+			res.headers_mut().extend(default_headers);
+			Ok(res)
+		}
+		Err(msg) => Err(msg.to_string()),
 	}
 }
 
@@ -626,6 +1465,75 @@ mod tests {
 	use lipsum::lipsum;
 	use std::{boxed::Box, io};
 
+	#[test]
+	fn test_sharded_cookies() {
+		let subscriptions = (0..600).map(|i| format!("sub{}", i)).collect::<Vec<_>>().join("+");
+
+		let mut res = Response::new(Body::empty());
+		res.insert_cookie(Cookie::new("subscriptions", subscriptions.clone()));
+		let set_cookies: Vec<Cookie> = res
+			.headers()
+			.get_all("Set-Cookie")
+			.iter()
+			.filter_map(|h| Cookie::parse(h.to_str().unwrap().to_string()).ok())
+			.collect();
+		assert!(set_cookies.iter().all(|c| c.to_string().len() < 4096));
+
+		// The browser keeps the cookies that were not expired and sends them back
+		let kept: Vec<String> = set_cookies
+			.iter()
+			.filter(|c| c.max_age().is_none())
+			.map(|c| format!("{}={}", c.name(), c.value()))
+			.collect();
+		assert!(kept.len() > 1);
+		let req = Request::builder().header("Cookie", format!("theme=dark; {}", kept.join("; "))).body(Body::empty()).unwrap();
+		assert_eq!(req.cookie("subscriptions").map(|c| c.value().to_string()), Some(subscriptions));
+		assert_eq!(req.cookie("theme").map(|c| c.value().to_string()), Some("dark".to_string()));
+		assert!(req.cookie("filters").is_none());
+	}
+
+	#[test]
+	fn test_shrinking_sharded_cookie() {
+		let set_cookies = |res: &Response<Body>| -> Vec<Cookie> {
+			res
+				.headers()
+				.get_all("Set-Cookie")
+				.iter()
+				.filter_map(|h| Cookie::parse(h.to_str().unwrap().to_string()).ok())
+				.collect()
+		};
+
+		let long = "a".repeat(SHARD_SIZE * 2 + 1);
+		let mut res = Response::new(Body::empty());
+		res.insert_cookie(Cookie::new("filters", long));
+		let written: Vec<String> = set_cookies(&res).iter().filter(|c| c.max_age().is_none()).map(|c| c.name().to_string()).collect();
+		assert_eq!(written, ["filters1", "filters2", "filters3"]);
+
+		// Shrinking to a single cookie expires every shard
+		let mut res = Response::new(Body::empty());
+		res.insert_cookie(Cookie::new("filters", "rust"));
+		let cookies = set_cookies(&res);
+		let expired: Vec<&str> = cookies.iter().filter(|c| c.max_age().is_some()).map(Cookie::name).collect();
+		for name in ["filters1", "filters2", "filters3"] {
+			assert!(expired.contains(&name));
+		}
+		assert!(cookies.iter().any(|c| c.name() == "filters" && c.value() == "rust" && c.max_age().is_none()));
+	}
+
+	#[test]
+	fn test_split_cookie_headers() {
+		// As HTTP/2 clients send them, one cookie per header
+		let req = Request::builder()
+			.header("Cookie", "theme=dark")
+			.header("Cookie", "subscriptions1=rust+")
+			.header("Cookie", "subscriptions2=linux; layout=card")
+			.body(Body::empty())
+			.unwrap();
+		assert_eq!(req.cookie("theme").map(|c| c.value().to_string()), Some("dark".to_string()));
+		assert_eq!(req.cookie("layout").map(|c| c.value().to_string()), Some("card".to_string()));
+		assert_eq!(req.cookie("subscriptions").map(|c| c.value().to_string()), Some("rust+linux".to_string()));
+	}
+
 	#[test]
 	fn test_determine_compressor() {
 		// Single compressor given.
@@ -643,6 +1551,123 @@ mod tests {
 		assert_eq!(determine_compressor("gzip;q=NAN".to_string()), None);
 	}
 
+	#[test]
+	fn test_etag_matches() {
+		let etag = weak_etag(42, &[1, 2, 3]);
+		assert!(etag.starts_with("W/\""));
+		assert_ne!(etag, weak_etag(42, &[1, 2]));
+		assert_ne!(etag, weak_etag(43, &[1, 2, 3]));
+
+		let headers = |value: &str| {
+			let mut req_headers = HeaderMap::new();
+			req_headers.insert(header::IF_NONE_MATCH, header::HeaderValue::from_str(value).unwrap());
+			req_headers
+		};
+
+		assert!(!etag_matches(&HeaderMap::new(), &etag));
+		assert!(etag_matches(&headers(&etag), &etag));
+		assert!(etag_matches(&headers("*"), &etag));
+		assert!(etag_matches(&headers(&format!("\"foo\", {}", etag)), &etag));
+		// Weak comparison ignores the W/ prefix
+		assert!(etag_matches(&headers(etag.trim_start_matches("W/")), &etag));
+		assert!(!etag_matches(&headers("W/\"foo\""), &etag));
+	}
+
+	#[test]
+	fn test_strip_base_path() {
+		let strip = |uri: &str, base_path: &str| {
+			let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+			strip_base_path(&mut req, base_path).then(|| req.uri().to_string())
+		};
+
+		assert_eq!(strip("/r/rust?sort=new", ""), Some("/r/rust?sort=new".to_string()));
+		assert_eq!(strip("/reddit/r/rust?sort=new", "/reddit"), Some("/r/rust?sort=new".to_string()));
+		assert_eq!(strip("/reddit", "/reddit"), Some("/".to_string()));
+		assert_eq!(strip("/reddit/", "/reddit"), Some("/".to_string()));
+		assert_eq!(strip("/redditor", "/reddit"), None);
+		assert_eq!(strip("/r/rust", "/reddit"), None);
+	}
+
+	#[test]
+	fn test_rebase_urls() {
+		assert_eq!(
+			rebase_urls(r#"<a href="/r/rust">r/rust</a><img src="/img/a.png"><form action="/settings">"#, "/reddit"),
+			r#"<a href="/reddit/r/rust">r/rust</a><img src="/reddit/img/a.png"><form action="/reddit/settings">"#
+		);
+		assert_eq!(rebase_urls(r#"<a href="/">home</a>"#, "/reddit"), r#"<a href="/reddit/">home</a>"#);
+		assert_eq!(
+			rebase_urls("<span style=\"background-image:url('/emoji/1/a')\">", "/reddit"),
+			"<span style=\"background-image:url('/reddit/emoji/1/a')\">"
+		);
+
+		// Leave external and protocol-relative links alone
+		let external = r#"<a href="https://reddit.com/r/rust">link</a><img src="//example.com/a.png">"#;
+		assert_eq!(rebase_urls(external, "/reddit"), external);
+
+		// Every link of a tag is rebased, but not text or other attributes
+		assert_eq!(
+			rebase_urls(r#"<video src="/vid/a" poster="/preview/b"></video>"#, "/reddit"),
+			r#"<video src="/reddit/vid/a" poster="/reddit/preview/b"></video>"#
+		);
+		let text = r#"<p>export PATH=/usr/bin x=/home &lt;a href=&quot;/r/rust&quot;&gt;</p><input name="redirect" value="/settings">"#;
+		assert_eq!(rebase_urls(text, "/reddit"), text);
+		assert_eq!(rebase_css_urls("src: url('/Inter.var.woff2');", "/reddit"), "src: url('/reddit/Inter.var.woff2');");
+
+		// Link-like text in other attribute values is left alone, even after a `>`
+		let attributes = r#"<a title="see > href=/r/rust" data-url="url(/x)" href="/r/rust">"#;
+		assert_eq!(
+			rebase_urls(attributes, "/reddit"),
+			r#"<a title="see > href=/r/rust" data-url="url(/x)" href="/reddit/r/rust">"#
+		);
+		assert_eq!(rebase_urls("<a href=/r/rust>", "/reddit"), "<a href=/reddit/r/rust>");
+
+		// Script bodies are left alone, while style bodies are CSS
+		let script = r#"<script>if (a<b && c>d) { el.innerHTML = '<a href="/r/rust">'; }</script>"#;
+		assert_eq!(rebase_urls(script, "/reddit"), script);
+		assert_eq!(
+			rebase_urls(r#"<style nonce="n">body { background: url("/bg.png"); } a<b>c { }</style><img src="/a.png">"#, "/reddit"),
+			r#"<style nonce="n">body { background: url("/reddit/bg.png"); } a<b>c { }</style><img src="/reddit/a.png">"#
+		);
+	}
+
+	#[test]
+	fn test_panic_message() {
+		let message = |payload: Box<dyn Any + Send>| panic_message(&*payload).to_string();
+
+		assert_eq!(message(Box::new("static")), "static");
+		assert_eq!(message(Box::new(format!("formatted {}", 1))), "formatted 1");
+		assert_eq!(message(Box::new(42)), "unknown cause");
+	}
+
+	#[test]
+	fn test_with_nonce() {
+		let (nonce, headers) = with_nonce(headers! { "Content-Security-Policy" => "style-src 'nonce-{nonce}'", "X-Frame-Options" => "DENY" });
+		assert_eq!(nonce.len(), 32);
+		assert_eq!(headers["Content-Security-Policy"], format!("style-src 'nonce-{}'", nonce));
+		assert_eq!(headers["X-Frame-Options"], "DENY");
+		assert_ne!(with_nonce(HeaderMap::new()).0, nonce);
+	}
+
+	#[test]
+	fn test_https_redirect() {
+		let location = |host: Option<&str>, uri: &str, port: u16| {
+			let mut req = Request::builder().uri(uri);
+			if let Some(host) = host {
+				req = req.header(header::HOST, host);
+			}
+			let res = https_redirect(&req.body(Body::empty()).unwrap(), port);
+			res.headers().get(header::LOCATION).map(|hdr| hdr.to_str().unwrap().to_owned())
+		};
+
+		assert_eq!(
+			location(Some("example.com"), "/r/rust?sort=new", 443),
+			Some("https://example.com/r/rust?sort=new".to_string())
+		);
+		assert_eq!(location(Some("example.com:80"), "/", 8443), Some("https://example.com:8443/".to_string()));
+		assert_eq!(location(Some("[::1]:8080"), "/settings", 443), Some("https://[::1]/settings".to_string()));
+		assert_eq!(location(None, "/", 443), None);
+	}
+
 	#[test]
 	fn test_compress_response() {
 		// This macro generates an Accept-Encoding header value given any number of
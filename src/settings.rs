@@ -1,12 +1,25 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
 
 // CRATES
-use crate::server::ResponseExt;
-use crate::utils::{redirect, template, Preferences};
+use crate::bookmarks;
+use crate::config::validate_preference;
+use crate::content_filter::{self, ContentFilter, Field};
+use crate::policy::Action;
+use crate::profile;
+use crate::server::{RequestExt, ResponseExt};
+use crate::utils::{
+	error, error_with_status, is_sub_name, param, redirect, setting, template, Feed, Preferences, COOKIE_ONLY_SETTINGS, FEED_SEPARATOR, SUB_PREFERENCES_SEPARATOR,
+};
 use askama::Template;
-use cookie::Cookie;
-use futures_lite::StreamExt;
-use hyper::{Body, Request, Response};
+use cookie::{Cookie, SameSite};
+use futures_lite::{future, StreamExt};
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Response, Uri};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use time::{Duration, OffsetDateTime};
 
 // STRUCTS
@@ -15,11 +28,74 @@ use time::{Duration, OffsetDateTime};
 struct SettingsTemplate {
 	prefs: Preferences,
 	url: String,
+	profiles: bool,
+	profile: Option<String>,
+	import: Option<ImportReport>,
+	subscriptions_import: Option<SubscriptionsReport>,
+	cookies_kb: Option<usize>,
+	/// Link to /settings/restore with every setting, which sets them all back.
+	restore_url: String,
+	/// Profile token waiting for the user to confirm linking this browser to it
+	link: Option<String>,
+}
+
+// Settings to set, or to remove when `None`
+type Changes = Vec<(String, Option<String>)>;
+
+// Outcome of /settings/import, shown on the settings page it redirects to
+struct ImportReport {
+	imported: String,
+	unknown: Vec<String>,
+	invalid: Vec<String>,
+}
+
+// Outcome of /settings/subscriptions, shown on the settings page it
+// redirects to
+#[derive(Default)]
+struct SubscriptionsReport {
+	imported: usize,
+	existing: usize,
+	not_found: ReportedNames,
+	banned: ReportedNames,
+	private: ReportedNames,
+	failed: ReportedNames,
+}
+
+// Subreddits in a SubscriptionsReport. Only the first MAX_REPORTED_NAMES are
+// listed, as they travel in the URL of the redirect, and the others counted.
+#[derive(Default)]
+struct ReportedNames {
+	names: Vec<String>,
+	more: usize,
+}
+
+impl ReportedNames {
+	fn push(&mut self, name: String) {
+		if self.names.len() < MAX_REPORTED_NAMES {
+			self.names.push(name);
+		} else {
+			self.more += 1;
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		self.names.is_empty() && self.more == 0
+	}
+}
+
+impl std::fmt::Display for ReportedNames {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.names.join(", "))?;
+		if self.more > 0 {
+			write!(f, " and {} more", self.more)?;
+		}
+		Ok(())
+	}
 }
 
 // CONSTANTS
 
-const PREFS: [&str; 13] = [
+const PREFS: [&str; 18] = [
 	"theme",
 	"front_page",
 	"layout",
@@ -33,21 +109,113 @@ const PREFS: [&str; 13] = [
 	"autoplay_videos",
 	"hide_awards",
 	"disable_visit_reddit_confirmation",
+	"min_score",
+	"min_ratio",
+	"post_types",
+	"hide_stickied",
+	"track_read",
+];
+
+// Settings that are kept in cookies and server-side profiles along with PREFS,
+// holding lists with the given separator
+const LISTS: [(&str, char); 8] = [
+	("subscriptions", '+'),
+	("filters", '+'),
+	("feeds", FEED_SEPARATOR),
+	("content_filters", content_filter::SEPARATOR),
+	("hidden_posts", '+'),
+	("read_posts", '+'),
+	("bookmarks", bookmarks::SEPARATOR),
+	("sub_preferences", SUB_PREFERENCES_SEPARATOR),
 ];
 
+// Version of the documents /settings/export produces and /settings/import reads
+const EXPORT_VERSION: i64 = 1;
+
+// Many servers and proxies refuse requests whose headers are larger than
+// 8 KB, so users are warned when their cookies come close
+const COOKIE_WARNING_SIZE: usize = 6 * 1024;
+
+// Uploads larger than this are refused by /settings/import and
+// /settings/subscriptions
+const MAX_IMPORT_SIZE: usize = 1024 * 1024;
+
+// Subreddits are looked up one by one, so imports are limited to this many,
+// checked this many at a time
+const MAX_IMPORTED_SUBSCRIPTIONS: usize = 500;
+const CONCURRENT_LOOKUPS: usize = 8;
+
+// Subreddits listed per kind of problem in the report of an import
+const MAX_REPORTED_NAMES: usize = 20;
+
 // FUNCTIONS
 
+// Names of the cookies of every setting
+fn names() -> impl Iterator<Item = &'static str> {
+	PREFS.into_iter().chain(LISTS.into_iter().map(|(name, _)| name))
+}
+
 // Retrieve cookies from request "Cookie" header
 pub async fn get(req: Request<Body>) -> Result<Response<Body>, String> {
+	settings_page(&req, None)
+}
+
+// Render the settings page, asking to confirm linking to the profile `link`
+// if given
+fn settings_page(req: &Request<Body>, link: Option<String>) -> Result<Response<Body>, String> {
 	let url = req.uri().to_string();
+
+	let query = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+		.into_owned()
+		.collect::<Vec<_>>();
+	let import = param(&url, "imported").map(|imported| ImportReport {
+		imported,
+		unknown: query.iter().filter(|(name, _)| name == "unknown").map(|(_, key)| key.clone()).collect(),
+		invalid: query.iter().filter(|(name, _)| name == "invalid").map(|(_, key)| key.clone()).collect(),
+	});
+
+	let list = |name: &str| ReportedNames {
+		names: query.iter().filter(|(key, _)| key == name).map(|(_, sub)| sub.clone()).take(MAX_REPORTED_NAMES).collect(),
+		more: param(&url, &format!("{}_more", name)).and_then(|more| more.parse().ok()).unwrap_or_default(),
+	};
+	let subscriptions_import = param(&url, "subscribed").map(|imported| SubscriptionsReport {
+		imported: imported.parse().unwrap_or_default(),
+		existing: param(&url, "existing").and_then(|existing| existing.parse().ok()).unwrap_or_default(),
+		not_found: list("not_found"),
+		banned: list("banned"),
+		private: list("private"),
+		failed: list("failed"),
+	});
+
+	let cookies_size = req.headers().get_all("Cookie").iter().map(|header| header.len()).sum::<usize>();
+
+	// Restoring removes every setting missing from the link, so all are included
+	let mut restore = url::form_urlencoded::Serializer::new(String::new());
+	for name in names() {
+		let value = setting(req, name);
+		if !value.is_empty() {
+			restore.append_pair(name, &value);
+		}
+	}
+	let restore_url = format!("/settings/restore/?{}", restore.finish());
+
 	template(SettingsTemplate {
-		prefs: Preferences::new(&req),
+		prefs: Preferences::new(req),
 		url,
+		profiles: profile::enabled(),
+		profile: profile::token(req),
+		import,
+		subscriptions_import,
+		cookies_kb: (cookies_size > COOKIE_WARNING_SIZE).then(|| cookies_size.div_ceil(1024)),
+		restore_url,
+		link,
 	})
 }
 
 // Set cookies using response "Set-Cookie" header
 pub async fn set(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+
 	// Split the body into parts
 	let (parts, mut body) = req.into_parts();
 
@@ -86,10 +254,16 @@ pub async fn set(req: Request<Body>) -> Result<Response<Body>, String> {
 		};
 	}
 
+	if let Some(token) = token {
+		profile::update(&token, PREFS.iter().map(|&name| (name.to_string(), form.get(name).map(|value| value.to_string()))))?;
+	}
+
 	Ok(response)
 }
 
-fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Response<Body> {
+fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+
 	// Split the body into parts
 	let (parts, _) = req.into_parts();
 
@@ -111,8 +285,9 @@ fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Response<Body
 	};
 
 	let mut response = redirect(path);
+	let mut changes = Vec::new();
 
-	for name in [PREFS.to_vec(), vec!["subscriptions", "filters"]].concat() {
+	for name in names() {
 		match form.get(name) {
 			Some(value) => response.insert_cookie(
 				Cookie::build(name.to_owned(), value.clone())
@@ -127,16 +302,646 @@ fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Response<Body
 				}
 			}
 		};
+		if (form.contains_key(name) || remove_cookies) && in_profile(name) {
+			changes.push((name.to_string(), form.get(name).map(|value| value.to_string())));
+		}
+	}
+
+	if let Some(token) = token {
+		profile::update(&token, changes)?;
 	}
 
-	response
+	Ok(response)
 }
 
 // Set cookies using response "Set-Cookie" header
 pub async fn restore(req: Request<Body>) -> Result<Response<Body>, String> {
-	Ok(set_cookies_method(req, true))
+	set_cookies_method(req, true)
 }
 
 pub async fn update(req: Request<Body>) -> Result<Response<Body>, String> {
-	Ok(set_cookies_method(req, false))
+	set_cookies_method(req, false)
+}
+
+// Create, link, unlink or delete a server-side profile
+pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
+	if !profile::enabled() {
+		return error(req, "Profiles are disabled on this instance".to_string()).await;
+	}
+
+	// Linking a browser to a profile hands the profile's owner control over
+	// its settings, so other sites must not be able to submit this form
+	if !same_origin(&req) {
+		return error_with_status(req, 403, "Profile changes must come from this instance").await;
+	}
+
+	let linked = profile::token(&req);
+
+	// Settings currently kept in cookies, for a new profile to start from
+	let cookies: profile::Profile = names()
+		.filter(|name| in_profile(name))
+		.filter_map(|name| req.cookie(name).map(|cookie| (name.to_string(), cookie.value().to_string())))
+		.collect();
+
+	let (parts, body) = req.into_parts();
+	let body_bytes = hyper::body::to_bytes(body).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+
+	let mut response = redirect("/settings".to_string());
+
+	match (form.get("action").map(String::as_str), linked) {
+		(Some("create"), None) => {
+			// Profiles take up room on the server, so creating one counts
+			// against the client's rate limit
+			if crate::ratelimit::charge().is_err() {
+				return error_with_status(Request::from_parts(parts, Body::empty()), 429, "Too many requests, please try again later").await;
+			}
+			let token = profile::create(cookies)?;
+			response.insert_cookie(profile_cookie(token));
+		}
+		(Some("link"), None) => {
+			let token = form.get("token").map(|token| token.trim().to_ascii_lowercase()).unwrap_or_default();
+			if !profile::is_token(&token) || !profile::exists(&token) {
+				return error_with_status(Request::from_parts(parts, Body::empty()), 404, "No profile with this token exists").await;
+			}
+			// The settings page asks before replacing this browser's settings
+			if form.get("confirm").map(String::as_str) != Some("on") {
+				return settings_page(&Request::from_parts(parts, Body::empty()), Some(token));
+			}
+			response.insert_cookie(profile_cookie(token));
+		}
+		// Unlinking and deleting leave the profile's settings behind in cookies,
+		// so that the browser keeps them
+		(Some("unlink"), Some(token)) => {
+			let settings = profile::find(&token).unwrap_or_default();
+			copy_to_cookies(&mut response, &settings);
+			response.remove_cookie(profile::COOKIE.to_string());
+		}
+		(Some("delete"), Some(token)) => {
+			let settings = profile::delete(&token)?.unwrap_or_default();
+			copy_to_cookies(&mut response, &settings);
+			response.remove_cookie(profile::COOKIE.to_string());
+		}
+		_ => return Err("Invalid profile action".to_string()),
+	}
+
+	Ok(response)
+}
+
+fn profile_cookie(token: String) -> Cookie<'static> {
+	Cookie::build(profile::COOKIE, token)
+		.path("/")
+		.http_only(true)
+		.same_site(SameSite::Strict)
+		.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+		.finish()
+}
+
+// Whether a form was submitted from a page of this instance. Browsers send
+// the page's origin along with POST requests; older ones that don't may
+// still say whether it was a cross-site request.
+fn same_origin(req: &Request<Body>) -> bool {
+	let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+
+	match header("Origin") {
+		Some(origin) => {
+			let host = header("Host").or_else(|| req.uri().authority().map(Authority::as_str));
+			origin
+				.parse::<Uri>()
+				.ok()
+				.and_then(|origin| origin.authority().cloned())
+				.is_some_and(|origin| Some(origin.as_str()) == host)
+		}
+		None => !matches!(header("Sec-Fetch-Site"), Some("cross-site" | "same-site")),
+	}
+}
+
+fn copy_to_cookies(response: &mut Response<Body>, settings: &profile::Profile) {
+	let changes: Changes = names()
+		.filter(|name| in_profile(name))
+		.map(|name| (name.to_string(), settings.get(name).cloned()))
+		.collect();
+	apply_to_cookies(response, &changes);
+}
+
+// Set the cookies of the settings that are `Some` and remove the others
+fn apply_to_cookies(response: &mut Response<Body>, changes: &[(String, Option<String>)]) {
+	for (name, value) in changes {
+		match value {
+			Some(value) => response.insert_cookie(
+				Cookie::build(name.clone(), value.clone())
+					.path("/")
+					.http_only(true)
+					.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+					.finish(),
+			),
+			None => response.remove_cookie(name.clone()),
+		};
+	}
+}
+
+// Set or remove settings in cookies, and in the profile the browser is linked
+// to, if any
+pub(crate) fn save(token: Option<&str>, response: &mut Response<Body>, changes: &[(String, Option<String>)]) -> Result<(), String> {
+	apply_to_cookies(response, changes);
+	match token {
+		Some(token) => profile::update(token, changes.iter().filter(|(name, _)| in_profile(name)).cloned()),
+		None => Ok(()),
+	}
+}
+
+// Whether a setting is kept in profiles, or only ever in cookies
+fn in_profile(name: &str) -> bool {
+	!COOKIE_ONLY_SETTINGS.contains(&name)
+}
+
+// Show every hidden post again, or forget which posts were read
+pub async fn history(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+
+	let name = match form.get("action").map(String::as_str) {
+		Some("unhide_all") => "hidden_posts",
+		Some("clear_read") => "read_posts",
+		_ => return Err("Invalid history action".to_string()),
+	};
+
+	let mut response = redirect("/settings".to_string());
+	save(token.as_deref(), &mut response, &[(name.to_string(), None)])?;
+	Ok(response)
+}
+
+// Create, change or delete a custom feed
+pub async fn feeds(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+	let mut feeds = Feed::all(&req);
+
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+	let field = |name: &str| form.get(name).map(|value| value.trim().to_string()).unwrap_or_default();
+
+	// Subreddits may be separated by spaces, commas or +, and be given as
+	// r/name, with users written as u/name
+	let list = |name: &str| -> Vec<String> {
+		field(name)
+			.split(|c: char| c == '+' || c == ',' || c.is_whitespace())
+			.map(|item| item.trim_start_matches('/'))
+			.filter(|item| !item.is_empty())
+			.map(|item| item.strip_prefix("r/").map_or_else(|| item.replacen("u/", "u_", 1), str::to_string))
+			.collect()
+	};
+
+	let name = field("name");
+	match field("action").as_str() {
+		"save" => {
+			let feed = Feed {
+				name,
+				subs: list("subreddits"),
+				sort: field("sort"),
+				filters: list("filters"),
+			};
+			if !Feed::is_valid(&feed.to_string()) {
+				return Err("Invalid feed: its name may only contain letters, digits, - and _, and it needs at least one subreddit".to_string());
+			}
+			match feeds.iter_mut().find(|existing| existing.name == feed.name) {
+				Some(existing) => *existing = feed,
+				None => feeds.push(feed),
+			}
+		}
+		"delete" => feeds.retain(|feed| feed.name != name),
+		_ => return Err("Invalid feed action".to_string()),
+	}
+
+	let value = (!feeds.is_empty()).then(|| feeds.iter().map(ToString::to_string).collect::<Vec<_>>().join(&FEED_SEPARATOR.to_string()));
+	let mut response = redirect("/settings".to_string());
+	apply_to_cookies(&mut response, &[("feeds".to_string(), value.clone())]);
+	if let Some(token) = token {
+		profile::update(&token, [("feeds".to_string(), value)])?;
+	}
+
+	Ok(response)
+}
+
+// Add or remove a keyword, flair, domain or comment filter
+pub async fn content_filters(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+	let mut filters = ContentFilter::all(&req);
+
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+	let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+
+	match field("action") {
+		"add" => {
+			let Some(target) = Field::ALL.into_iter().find(|target| target.name() == field("field")) else {
+				return Err("Invalid filter field".to_string());
+			};
+			let action = match field("filter_action") {
+				"collapse" => Action::Collapse,
+				_ => Action::Hide,
+			};
+			let filter = ContentFilter::new(target, action, field("pattern"))?;
+			if !filters.iter().any(|existing| existing.to_string() == filter.to_string()) {
+				filters.push(filter);
+			}
+		}
+		"remove" => filters.retain(|filter| filter.to_string() != field("filter")),
+		_ => return Err("Invalid filter action".to_string()),
+	}
+
+	let value = (!filters.is_empty()).then(|| filters.iter().map(ToString::to_string).collect::<Vec<_>>().join(&content_filter::SEPARATOR.to_string()));
+	let mut response = redirect("/settings".to_string());
+	apply_to_cookies(&mut response, &[("content_filters".to_string(), value.clone())]);
+	if let Some(token) = token {
+		profile::update(&token, [("content_filters".to_string(), value)])?;
+	}
+
+	Ok(response)
+}
+
+// Download every setting as a JSON or TOML document
+pub async fn export(req: Request<Body>) -> Result<Response<Body>, String> {
+	use toml::Value;
+
+	let mut document = toml::Table::new();
+	document.insert("version".to_string(), Value::Integer(EXPORT_VERSION));
+	for name in PREFS {
+		document.insert(name.to_string(), Value::String(setting(&req, name)));
+	}
+	for (name, separator) in LISTS {
+		let list = setting(&req, name)
+			.split(separator)
+			.filter(|item| !item.is_empty())
+			.map(|item| Value::String(item.to_string()))
+			.collect();
+		document.insert(name.to_string(), Value::Array(list));
+	}
+
+	let (body, content_type, extension) = match param(&req.uri().to_string(), "format").as_deref() {
+		Some("toml") => (toml::to_string(&document).map_err(|e| e.to_string())?, "application/toml", "toml"),
+		_ => (serde_json::to_string_pretty(&document).map_err(|e| e.to_string())?, "application/json", "json"),
+	};
+
+	Response::builder()
+		.status(200)
+		.header("content-type", content_type)
+		.header("content-disposition", format!("attachment; filename=\"libreddit-settings.{}\"", extension))
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+// Apply the settings of an uploaded document, as produced by export
+pub async fn import(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+	let (parts, mut body) = req.into_parts();
+
+	let mut bytes = Vec::new();
+	while let Some(chunk) = body.next().await {
+		bytes.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+		if bytes.len() > MAX_IMPORT_SIZE {
+			return error_with_status(Request::from_parts(parts, Body::empty()), 413, "The settings file is too large").await;
+		}
+	}
+
+	let content_type = parts.headers.get("content-type").and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+	let document = match uploaded_document(&content_type, &bytes)
+		.ok_or_else(|| "No settings file was uploaded".to_string())
+		.and_then(|document| parse_document(&document))
+	{
+		Ok(document) => document,
+		Err(msg) => return error_with_status(Request::from_parts(parts, Body::empty()), 400, msg).await,
+	};
+
+	let (changes, unknown, invalid) = validate_document(&document);
+
+	let mut query = url::form_urlencoded::Serializer::new(String::new());
+	query.append_pair("imported", &changes.len().to_string());
+	for key in &unknown {
+		query.append_pair("unknown", key);
+	}
+	for problem in &invalid {
+		query.append_pair("invalid", problem);
+	}
+
+	let mut response = redirect(format!("/settings?{}", query.finish()));
+	apply_to_cookies(&mut response, &changes);
+	if let Some(token) = token {
+		profile::update(&token, changes)?;
+	}
+
+	Ok(response)
+}
+
+// Subscribe to the subreddits listed in a Reddit data export
+// (subscribed_subreddits.csv) or an OPML file of feeds
+pub async fn import_subscriptions(req: Request<Body>) -> Result<Response<Body>, String> {
+	let token = profile::token(&req);
+	let mut subscriptions = setting(&req, "subscriptions")
+		.split('+')
+		.filter(|sub| !sub.is_empty())
+		.map(String::from)
+		.collect::<Vec<_>>();
+	let mut filters = setting(&req, "filters").split('+').filter(|sub| !sub.is_empty()).map(String::from).collect::<Vec<_>>();
+	let (parts, mut body) = req.into_parts();
+
+	let mut bytes = Vec::new();
+	while let Some(chunk) = body.next().await {
+		bytes.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+		if bytes.len() > MAX_IMPORT_SIZE {
+			return error_with_status(Request::from_parts(parts, Body::empty()), 413, "The subscriptions file is too large").await;
+		}
+	}
+
+	let content_type = parts.headers.get("content-type").and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+	let names = uploaded_document(&content_type, &bytes).map(|document| subscription_names(&document)).unwrap_or_default();
+	if names.is_empty() {
+		return error_with_status(Request::from_parts(parts, Body::empty()), 400, "No subreddits were found in the uploaded file").await;
+	}
+	if names.len() > MAX_IMPORTED_SUBSCRIPTIONS {
+		let msg = format!(
+			"The file lists {} subreddits, but at most {} can be imported at once",
+			names.len(),
+			MAX_IMPORTED_SUBSCRIPTIONS
+		);
+		return error_with_status(Request::from_parts(parts, Body::empty()), 400, msg).await;
+	}
+
+	// Look the subreddits up like /r/{sub}/subscribe does, to subscribe to
+	// them under their canonical names. Each lookup counts against the
+	// client's rate limit; those over it are reported as failed.
+	let mut report = SubscriptionsReport::default();
+	let mut limited = false;
+	for chunk in names.chunks(CONCURRENT_LOOKUPS) {
+		let lookups = chunk.iter().map(|name| {
+			let allowed = name.starts_with("u_") || (!limited && crate::ratelimit::charge().is_ok());
+			limited |= !allowed;
+			async move {
+				let display_name = if name.starts_with("u_") {
+					Ok(name.clone())
+				} else if allowed {
+					crate::subreddit::display_name(name).await
+				} else {
+					Err("Too many requests".to_string())
+				};
+				(name.clone(), display_name)
+			}
+		});
+
+		for (name, display_name) in join_all(lookups.collect()).await {
+			match display_name {
+				Ok(sub) if subscriptions.iter().any(|subscribed| subscribed.eq_ignore_ascii_case(&sub)) => report.existing += 1,
+				Ok(sub) => {
+					filters.retain(|filtered| !filtered.eq_ignore_ascii_case(&sub));
+					subscriptions.push(sub);
+					report.imported += 1;
+				}
+				Err(msg) => match msg.as_str() {
+					"banned" => report.banned.push(name),
+					"private" => report.private.push(name),
+					"Not Found" | "Failed to query subreddit name" => report.not_found.push(name),
+					_ => report.failed.push(name),
+				},
+			}
+		}
+	}
+	subscriptions.sort_by_key(|sub| sub.to_lowercase());
+
+	let mut query = url::form_urlencoded::Serializer::new(String::new());
+	query.append_pair("subscribed", &report.imported.to_string());
+	query.append_pair("existing", &report.existing.to_string());
+	for (key, names) in [
+		("not_found", &report.not_found),
+		("banned", &report.banned),
+		("private", &report.private),
+		("failed", &report.failed),
+	] {
+		for name in &names.names {
+			query.append_pair(key, name);
+		}
+		if names.more > 0 {
+			query.append_pair(&format!("{}_more", key), &names.more.to_string());
+		}
+	}
+
+	let mut response = redirect(format!("/settings?{}", query.finish()));
+	let changes = [("subscriptions", subscriptions), ("filters", filters)].map(|(name, list)| (name.to_string(), (!list.is_empty()).then(|| list.join("+"))));
+	save(token.as_deref(), &mut response, &changes)?;
+	Ok(response)
+}
+
+// Runs futures concurrently within the current task, unlike tokio::spawn,
+// so that they share its task-locals, and returns their outputs in order
+async fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+	let mut futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+	let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+	future::poll_fn(|cx| {
+		for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+			if output.is_none() {
+				if let Poll::Ready(value) = future.as_mut().poll(cx) {
+					*output = Some(value);
+				}
+			}
+		}
+		if outputs.iter().all(Option::is_some) {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	})
+	.await;
+	outputs.into_iter().flatten().collect()
+}
+
+// Find the subreddits in a subscribed_subreddits.csv file, which lists one
+// per line under a `subreddit` header, or in the feed URLs of an OPML file.
+// User profiles are kept as `u_{name}`, like in subscriptions.
+fn subscription_names(document: &str) -> Vec<String> {
+	static OPML_FEED: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?i)(?:xmlUrl|htmlUrl|url)="[^"]*?/(r|u|user)/([A-Za-z0-9_+\-]+)"#).unwrap());
+
+	let names: Vec<String> = if document.contains("<opml") {
+		OPML_FEED
+			.captures_iter(document)
+			.flat_map(|feed| {
+				let prefix = if &feed[1] == "r" { "" } else { "u_" };
+				feed[2].split('+').map(|name| format!("{}{}", prefix, name)).collect::<Vec<_>>()
+			})
+			.collect()
+	} else {
+		document
+			.lines()
+			.filter_map(|line| line.split(',').next())
+			.map(|name| name.trim().trim_matches('"').trim_start_matches("/r/").trim_start_matches("r/").to_string())
+			.filter(|name| !name.eq_ignore_ascii_case("subreddit"))
+			.collect()
+	};
+
+	let mut unique: Vec<String> = Vec::new();
+	for name in names {
+		if is_sub_name(&name) && !unique.iter().any(|seen| seen.eq_ignore_ascii_case(&name)) {
+			unique.push(name);
+		}
+	}
+	unique
+}
+
+// Extract the document from a multipart form's `file` field, or take the
+// whole body when it was uploaded on its own
+fn uploaded_document(content_type: &str, body: &[u8]) -> Option<String> {
+	let body = String::from_utf8_lossy(body);
+
+	let Some(boundary) = content_type
+		.strip_prefix("multipart/form-data")
+		.and_then(|params| params.split(';').find_map(|param| param.trim().strip_prefix("boundary=")))
+	else {
+		return Some(body.into_owned()).filter(|body| !body.trim().is_empty());
+	};
+
+	body.split(&format!("--{}", boundary.trim_matches('"'))).find_map(|part| {
+		let (headers, content) = part.trim_start_matches("\r\n").split_once("\r\n\r\n")?;
+		let content = content.strip_suffix("\r\n").unwrap_or(content);
+		(headers.contains("name=\"file\"") && !content.trim().is_empty()).then(|| content.to_string())
+	})
+}
+
+// Parse a JSON or TOML settings document and check its version
+fn parse_document(document: &str) -> Result<toml::Table, String> {
+	let table: toml::Table = if document.trim_start().starts_with('{') {
+		serde_json::from_str(document).map_err(|e| format!("Failed to parse the settings file as JSON: {}", e))?
+	} else {
+		toml::from_str(document).map_err(|e| format!("Failed to parse the settings file as TOML: {}", e))?
+	};
+
+	match table.get("version") {
+		Some(toml::Value::Integer(EXPORT_VERSION)) => Ok(table),
+		Some(version) => Err(format!("Unsupported settings file version {}", version)),
+		None => Err("The settings file has no version".to_string()),
+	}
+}
+
+// Split a document into the changes to apply, its unknown keys and its
+// invalid values. Empty values reset a setting to the instance default.
+fn validate_document(document: &toml::Table) -> (Changes, Vec<String>, Vec<String>) {
+	let (mut changes, mut unknown, mut invalid) = (Vec::new(), Vec::new(), Vec::new());
+
+	for (key, value) in document.iter().filter(|(key, _)| *key != "version") {
+		if !names().any(|name| name == key) {
+			unknown.push(key.clone());
+			continue;
+		}
+		let empty = value.as_str().is_some_and(str::is_empty) || value.as_array().is_some_and(Vec::is_empty);
+		match validate_preference(key, value) {
+			_ if empty => changes.push((key.clone(), None)),
+			Ok(value) => changes.push((key.clone(), Some(value))),
+			Err(e) => invalid.push(format!("{}: {}", key, e)),
+		}
+	}
+
+	(changes, unknown, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_subscription_names() {
+		let csv = "subreddit\r\nrust\r\n\"AskHistorians\"\r\nRust\r\n../etc\r\n";
+		assert_eq!(subscription_names(csv), ["rust", "AskHistorians"]);
+
+		let opml = r#"<?xml version="1.0"?>
+<opml version="2.0"><body>
+	<outline text="Reddit">
+		<outline type="rss" text="r/linux" xmlUrl="https://www.reddit.com/r/linux/.rss" htmlUrl="https://www.reddit.com/r/linux"/>
+		<outline type="rss" text="multi" xmlUrl="https://old.reddit.com/r/pics+aww/.rss"/>
+		<outline type="rss" text="spez" xmlUrl="https://www.reddit.com/user/spez/.rss"/>
+		<outline type="rss" text="Blog" xmlUrl="https://blog.example.com/feed.xml"/>
+	</outline>
+</body></opml>"#;
+		assert_eq!(subscription_names(opml), ["linux", "pics", "aww", "u_spez"]);
+	}
+
+	#[test]
+	fn test_join_all() {
+		// Outputs come back in order even when later futures finish first
+		let futures = (0..4)
+			.map(|i| async move {
+				for _ in i..4 {
+					future::yield_now().await;
+				}
+				i
+			})
+			.collect();
+		assert_eq!(future::block_on(join_all(futures)), [0, 1, 2, 3]);
+	}
+
+	#[test]
+	fn test_same_origin() {
+		let request = |headers: &[(&str, &str)]| {
+			let mut req = Request::post("/settings/profile").header("Host", "libreddit.example:8080");
+			for (name, value) in headers {
+				req = req.header(*name, *value);
+			}
+			req.body(Body::empty()).unwrap()
+		};
+
+		assert!(same_origin(&request(&[("Origin", "https://libreddit.example:8080")])));
+		assert!(!same_origin(&request(&[("Origin", "https://evil.example")])));
+		assert!(!same_origin(&request(&[("Origin", "null")])));
+		assert!(same_origin(&request(&[("Sec-Fetch-Site", "same-origin")])));
+		assert!(!same_origin(&request(&[("Sec-Fetch-Site", "cross-site")])));
+		assert!(same_origin(&request(&[])));
+	}
+
+	#[test]
+	fn test_hyphenated_filter_round_trip() {
+		let req = Request::get("/settings/export?format=toml")
+			.header("Cookie", "filters=rust+u_some-user")
+			.body(Body::empty())
+			.unwrap();
+		let response = future::block_on(export(req)).unwrap();
+		let body = future::block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+
+		let document = parse_document(&String::from_utf8_lossy(&body)).unwrap();
+		let (changes, _, invalid) = validate_document(&document);
+		assert!(invalid.is_empty());
+		assert!(changes.contains(&("filters".to_string(), Some("rust+u_some-user".to_string()))));
+
+		assert_eq!(subscription_names("subreddit\nu_some-user\n"), ["u_some-user"]);
+		assert!(Feed::parse("mine:rust::u_some-user").is_some());
+	}
+
+	#[test]
+	fn test_uploaded_document() {
+		let body = "--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"s.json\"\r\nContent-Type: application/json\r\n\r\n{\"version\": 1}\r\n--xyz--\r\n";
+		assert_eq!(
+			uploaded_document("multipart/form-data; boundary=xyz", body.as_bytes()),
+			Some("{\"version\": 1}".to_string())
+		);
+		assert_eq!(uploaded_document("multipart/form-data; boundary=\"xyz\"", b"--xyz--\r\n"), None);
+		assert_eq!(uploaded_document("application/toml", b"version = 1"), Some("version = 1".to_string()));
+	}
+
+	#[test]
+	fn test_validate_document() {
+		assert!(parse_document("version = 2").is_err());
+		assert!(parse_document("{\"theme\": \"dark\"}").is_err());
+
+		let document =
+			parse_document("version = 1\ntheme = \"dark\"\nwide = true\nlayout = \"grid\"\nsubscriptions = [\"rust\", \"linux\"]\nfilters = []\nfont = \"serif\"").unwrap();
+		let (changes, unknown, invalid) = validate_document(&document);
+		assert_eq!(
+			changes,
+			vec![
+				("filters".to_string(), None),
+				("subscriptions".to_string(), Some("rust+linux".to_string())),
+				("theme".to_string(), Some("dark".to_string())),
+				("wide".to_string(), Some("on".to_string())),
+			]
+		);
+		assert_eq!(unknown, vec!["font".to_string()]);
+		assert_eq!(invalid.len(), 1);
+		assert!(invalid[0].starts_with("layout: "));
+	}
 }
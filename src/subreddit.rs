@@ -1,11 +1,14 @@
 // CRATES
+use crate::content_filter::ContentFilter;
 use crate::utils::{
-	catch_random, error, filter_posts, format_num, format_url, get_filters, nsfw_landing, param, redirect, rewrite_urls, setting, template, val, Post, Preferences, Subreddit,
+	catch_random, error, filter_posts, format_num, format_url, get_filters, is_sub_name, local_path, nsfw_landing, param, redirect, rewrite_urls, setting, template, val, Feed,
+	ListingFilter, Post, Preferences, SubPreferences, Subreddit, SUB_PREFERENCES_SEPARATOR,
 };
-use crate::{client::json, server::ResponseExt, RequestExt};
+use crate::{blocklist, client::json, server::ResponseExt, settings, RequestExt};
 use askama::Template;
 use cookie::Cookie;
 use hyper::{Body, Request, Response};
+use std::collections::HashMap;
 use time::{Duration, OffsetDateTime};
 
 // STRUCTS
@@ -19,14 +22,21 @@ struct SubredditTemplate {
 	prefs: Preferences,
 	url: String,
 	redirect_url: String,
+	/// Name of the custom feed being shown, if any.
+	feed: String,
 	/// Whether the subreddit itself is filtered.
 	is_filtered: bool,
 	/// Whether all fetched posts are filtered (to differentiate between no posts fetched in the first place,
 	/// and all fetched posts being filtered).
 	all_posts_filtered: bool,
+	/// Number of fetched posts that were filtered.
+	num_posts_filtered: u64,
+	listing_filter: ListingFilter,
 	/// Whether all posts were hidden because they are NSFW (and user has disabled show NSFW)
 	all_posts_hidden_nsfw: bool,
 	no_posts: bool,
+	/// The subreddit's own sort and layout preferences.
+	sub_prefs: SubPreferences,
 }
 
 #[derive(Template)]
@@ -55,18 +65,38 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 	let root = req.uri().path() == "/";
 	let subscribed = setting(&req, "subscriptions");
 	let front_page = setting(&req, "front_page");
-	let post_sort = req.cookie("post_sort").map_or_else(|| "hot".to_string(), |c| c.value().to_string());
+
+	// Custom feeds are multireddits with their own default sort and filters
+	let feed = match req.param("feed") {
+		Some(name) => match Feed::all(&req).into_iter().find(|feed| feed.name == name) {
+			Some(feed) => Some(feed),
+			None => return error(req, format!("There is no feed named {}", name)).await,
+		},
+		None => None,
+	};
+
+	// A sort in the URL wins over the subreddit's own default sort, which wins
+	// over the global one
+	let sub_prefs = SubPreferences::get(&req, &req.param("sub").unwrap_or_default());
+	let post_sort = match &feed {
+		Some(feed) if !feed.sort.is_empty() => feed.sort.clone(),
+		_ if !sub_prefs.post_sort.is_empty() => sub_prefs.post_sort.clone(),
+		_ => req.cookie("post_sort").map_or_else(|| "hot".to_string(), |c| c.value().to_string()),
+	};
 	let sort = req.param("sort").unwrap_or_else(|| req.param("id").unwrap_or(post_sort));
 
-	let sub_name = req.param("sub").unwrap_or(if front_page == "default" || front_page.is_empty() {
-		if subscribed.is_empty() {
-			"popular".to_string()
+	let sub_name = match &feed {
+		Some(feed) => feed.subs.join("+"),
+		None => req.param("sub").unwrap_or(if front_page == "default" || front_page.is_empty() {
+			if subscribed.is_empty() {
+				"popular".to_string()
+			} else {
+				subscribed.clone()
+			}
 		} else {
-			subscribed.clone()
-		}
-	} else {
-		front_page.clone()
-	});
+			front_page.clone()
+		}),
+	};
 	let quarantined = can_access_quarantine(&req, &sub_name) || root;
 
 	// Handle random subreddits
@@ -78,8 +108,18 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(redirect(["/user/", &sub_name[2..]].concat()));
 	}
 
+	// Refuse blocked subreddits, and leave them out of multireddits
+	let Some(allowed_subs) = blocklist::allowed_subreddits(&sub_name) else {
+		return blocklist::unavailable(req).await;
+	};
+
 	// Request subreddit metadata
-	let sub = if !sub_name.contains('+') && sub_name != subscribed && sub_name != "popular" && sub_name != "all" {
+	let sub = if feed.is_some() {
+		Subreddit {
+			name: sub_name.clone(),
+			..Subreddit::default()
+		}
+	} else if !sub_name.contains('+') && sub_name != subscribed && sub_name != "popular" && sub_name != "all" {
 		// Regular subreddit
 		subreddit(&sub_name, quarantined).await.unwrap_or_default()
 	} else if sub_name == subscribed {
@@ -104,10 +144,16 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(nsfw_landing(req, req_url).await.unwrap_or_default());
 	}
 
-	let path = format!("/r/{}/{}.json?{}&raw_json=1", sub_name.clone(), sort, req.uri().query().unwrap_or_default());
+	let path = format!("/r/{}/{}.json?{}&raw_json=1", allowed_subs, sort, req.uri().query().unwrap_or_default());
 	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
 	let redirect_url = url[1..].replace('?', "%3F").replace('&', "%26").replace('+', "%2B");
-	let filters = get_filters(&req);
+	let mut filters = get_filters(&req);
+	let content_filters = ContentFilter::all(&req);
+	let listing_filter = ListingFilter::new(&req);
+	if let Some(feed) = &feed {
+		filters.extend(feed.filters.iter().cloned());
+	}
+	let feed = feed.map(|feed| feed.name).unwrap_or_default();
 
 	// If all requested subs are filtered, we don't need to fetch posts.
 	if sub_name.split('+').all(|s| filters.contains(s)) {
@@ -116,18 +162,22 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 			posts: Vec::new(),
 			sort: (sort, param(&path, "t").unwrap_or_default()),
 			ends: (param(&path, "after").unwrap_or_default(), "".to_string()),
-			prefs: Preferences::new(&req),
+			prefs: Preferences::for_sub(&req, &sub_prefs.sub),
 			url,
 			redirect_url,
+			feed,
 			is_filtered: true,
 			all_posts_filtered: false,
+			num_posts_filtered: 0,
+			listing_filter,
 			all_posts_hidden_nsfw: false,
 			no_posts: false,
+			sub_prefs,
 		})
 	} else {
 		match Post::fetch(&path, quarantined).await {
 			Ok((mut posts, after)) => {
-				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				let (num_posts_filtered, all_posts_filtered) = filter_posts(&mut posts, &filters, &content_filters, &listing_filter);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
 				template(SubredditTemplate {
@@ -135,13 +185,17 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 					posts,
 					sort: (sort, param(&path, "t").unwrap_or_default()),
 					ends: (param(&path, "after").unwrap_or_default(), after),
-					prefs: Preferences::new(&req),
+					prefs: Preferences::for_sub(&req, &sub_prefs.sub),
 					url,
 					redirect_url,
+					feed,
 					is_filtered: false,
 					all_posts_filtered,
+					num_posts_filtered,
+					listing_filter,
 					all_posts_hidden_nsfw,
 					no_posts,
+					sub_prefs,
 				})
 			}
 			Err(msg) => match msg.as_str() {
@@ -191,6 +245,16 @@ pub fn can_access_quarantine(req: &Request<Body>, sub: &str) -> bool {
 	setting(req, &format!("allow_quaran_{}", sub.to_lowercase())).parse().unwrap_or_default()
 }
 
+/// Looks up the canonical display name of a subreddit, as in `r/Rust` for
+/// `r/rust`, through its `about.json`.
+pub async fn display_name(sub: &str) -> Result<String, String> {
+	let about = json(format!("/r/{}/about.json?raw_json=1", sub), true).await?;
+	about["data"]["display_name"]
+		.as_str()
+		.map(String::from)
+		.ok_or_else(|| "Failed to query subreddit name".to_string())
+}
+
 // Sub, filter, unfilter, or unsub by setting subscription cookie using response "Set-Cookie" header
 pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sub = req.param("sub").unwrap_or_default();
@@ -228,6 +292,10 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 
 	// Find each subreddit name (separated by '+') in sub parameter
 	for part in sub.split('+').filter(|x| x != &"") {
+		if !is_sub_name(part) {
+			return Err(format!("Invalid subreddit or user name: {}", part));
+		}
+
 		// Retrieve display name for the subreddit
 		let display;
 		let part = if part.starts_with("u_") {
@@ -237,9 +305,8 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 			display
 		} else {
 			// This subreddit display name isn't known, retrieve it
-			let path: String = format!("/r/{}/about.json?raw_json=1", part);
-			display = json(path, true).await?;
-			display["data"]["display_name"].as_str().ok_or_else(|| "Failed to query subreddit name".to_string())?
+			display = display_name(part).await?;
+			&display
 		};
 
 		// Modify sub list based on action
@@ -276,6 +343,13 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 
 	let mut response = redirect(path);
 
+	if let Some(token) = crate::profile::token(&req) {
+		crate::profile::update(
+			&token,
+			[("subscriptions", &sub_list), ("filters", &filters)].map(|(name, list)| (name.to_string(), (!list.is_empty()).then(|| list.join("+")))),
+		)?;
+	}
+
 	// Delete cookie if empty, else set
 	if sub_list.is_empty() {
 		response.remove_cookie("subscriptions".to_string());
@@ -303,6 +377,41 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 	Ok(response)
 }
 
+// Set or reset the post sort, comment sort and layout of a subreddit
+pub async fn preferences(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+	let token = crate::profile::token(&req);
+	let mut all = SubPreferences::all(&req);
+
+	let body_bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string())?;
+	let form = url::form_urlencoded::parse(&body_bytes).into_owned().collect::<HashMap<String, String>>();
+	let field = |name: &str| form.get(name).cloned().unwrap_or_default();
+
+	let prefs = SubPreferences {
+		sub: sub.clone(),
+		post_sort: field("post_sort"),
+		comment_sort: field("comment_sort"),
+		layout: field("layout"),
+	};
+	all.retain(|other| !other.sub.eq_ignore_ascii_case(&sub));
+	if !prefs.is_empty() {
+		if !SubPreferences::is_valid(&prefs.to_string()) {
+			return Err(format!("Invalid preferences for r/{}", sub));
+		}
+		all.push(prefs);
+		all.sort_by_key(|prefs| prefs.sub.to_lowercase());
+	}
+
+	let value = (!all.is_empty()).then(|| all.iter().map(ToString::to_string).collect::<Vec<_>>().join(&SUB_PREFERENCES_SEPARATOR.to_string()));
+	let path = match form.get("redirect") {
+		Some(path) => local_path(Some(path.clone())),
+		None => format!("/r/{}", sub),
+	};
+	let mut response = redirect(path);
+	settings::save(token.as_deref(), &mut response, &[("sub_preferences".to_string(), value)])?;
+	Ok(response)
+}
+
 pub async fn wiki(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sub = req.param("sub").unwrap_or_else(|| "reddit.com".to_string());
 	let quarantined = can_access_quarantine(&req, &sub);
@@ -311,6 +420,10 @@ pub async fn wiki(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(random);
 	}
 
+	if blocklist::subreddit_blocked(&sub) {
+		return blocklist::unavailable(req).await;
+	}
+
 	let page = req.param("page").unwrap_or_else(|| "index".to_string());
 	let path: String = format!("/r/{}/wiki/{}.json?raw_json=1", sub, page);
 	let url = req.uri().to_string();
@@ -342,6 +455,10 @@ pub async fn sidebar(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(random);
 	}
 
+	if blocklist::subreddit_blocked(&sub) {
+		return blocklist::unavailable(req).await;
+	}
+
 	// Build the Reddit JSON API url
 	let path: String = format!("/r/{}/about.json?raw_json=1", sub);
 	let url = req.uri().to_string();
@@ -377,7 +494,7 @@ pub async fn sidebar(req: Request<Body>) -> Result<Response<Body>, String> {
 // 		moderators_list(sub, quarantined)
 // 			.await?
 // 			.iter()
-// 			.map(|m| format!("<li><a style=\"color: var(--accent)\" href=\"/u/{name}\">{name}</a></li>", name = m))
+// 			.map(|m| format!("<li><a class=\"sidebar_moderator\" href=\"/u/{name}\">{name}</a></li>", name = m))
 // 			.collect(),
 // 	)
 // }
@@ -0,0 +1,145 @@
+// CRATES
+use futures_lite::stream;
+use hyper::server::accept::{self, Accept};
+use rustls::{
+	server::{ClientHello, ResolvesServerCert},
+	sign::{self, CertifiedKey},
+	Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+use std::{
+	fs::{self, File},
+	io::{self, BufReader},
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	time::{Duration, SystemTime},
+};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::mpsc,
+	time,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the certificate currently loaded from disk. The certificate can be
+/// swapped while the server is running, so that renewed certificates are
+/// picked up without a restart.
+struct Resolver {
+	current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+	fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		self.current.read().ok().map(|key| key.clone())
+	}
+}
+
+/// Creates a TLS acceptor for the PEM-encoded certificate chain and private
+/// key at the given paths. Both files are watched, and reloaded whenever one
+/// of them changes. If a changed certificate cannot be loaded, the previous
+/// one keeps being served.
+pub fn acceptor(cert: PathBuf, key: PathBuf) -> io::Result<TlsAcceptor> {
+	let resolver = Arc::new(Resolver {
+		current: RwLock::new(load(&cert, &key)?),
+	});
+
+	tokio::spawn(watch(cert, key, resolver.clone()));
+
+	let mut config = ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(resolver);
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts TLS connections on `listener`. Handshakes are run in the
+/// background so that a slow client can't hold up everyone else.
+pub fn incoming(listener: TcpListener, acceptor: TlsAcceptor) -> impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error> {
+	let (tx, rx) = mpsc::channel(32);
+
+	tokio::spawn(async move {
+		loop {
+			// Stop accepting connections once the server stops asking for them
+			let conn = tokio::select! {
+				_ = tx.closed() => break,
+				conn = listener.accept() => conn,
+			};
+
+			let stream = match conn {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					// Back off when running out of file descriptors, for example
+					eprintln!("Failed to accept connection: {}", e);
+					time::sleep(Duration::from_millis(100)).await;
+					continue;
+				}
+			};
+
+			let _ = stream.set_nodelay(true);
+			let acceptor = acceptor.clone();
+			let tx = tx.clone();
+
+			tokio::spawn(async move {
+				// Failed handshakes are the client's problem, so they are dropped silently
+				if let Ok(Ok(conn)) = time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+					let _ = tx.send(conn).await;
+				}
+			});
+		}
+	});
+
+	accept::from_stream(stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|conn| (Ok(conn), rx)) }))
+}
+
+/// Periodically checks the certificate and key files for changes.
+async fn watch(cert: PathBuf, key: PathBuf, resolver: Arc<Resolver>) {
+	let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+	let mut last: (Option<SystemTime>, Option<SystemTime>) = (modified(&cert), modified(&key));
+
+	loop {
+		time::sleep(RELOAD_INTERVAL).await;
+
+		let current = (modified(&cert), modified(&key));
+		if current == last {
+			continue;
+		}
+		last = current;
+
+		match load(&cert, &key) {
+			Ok(certified) => {
+				if let Ok(mut current) = resolver.current.write() {
+					*current = certified;
+					println!("Reloaded TLS certificate from {}", cert.display());
+				}
+			}
+			Err(e) => eprintln!("Failed to reload TLS certificate, keeping the previous one: {}", e),
+		}
+	}
+}
+
+/// Loads a certificate chain and its private key from PEM files.
+fn load(cert: &Path, key: &Path) -> io::Result<Arc<CertifiedKey>> {
+	let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+	let chain: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?.into_iter().map(Certificate).collect();
+	if chain.is_empty() {
+		return Err(invalid(format!("no certificate found in {}", cert.display())));
+	}
+
+	let private_key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+		.into_iter()
+		.find_map(|item| match item {
+			Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+			_ => None,
+		})
+		.ok_or_else(|| invalid(format!("no private key found in {}", key.display())))?;
+
+	let signing_key = sign::any_supported_type(&private_key).map_err(|e| invalid(format!("unsupported private key in {}: {}", key.display(), e)))?;
+
+	Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
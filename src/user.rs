@@ -1,7 +1,9 @@
 // CRATES
+use crate::blocklist;
 use crate::client::json;
+use crate::content_filter::ContentFilter;
 use crate::server::RequestExt;
-use crate::utils::{error, filter_posts, format_url, get_filters, nsfw_landing, param, setting, template, Post, Preferences, User};
+use crate::utils::{error, filter_posts, format_url, get_filters, nsfw_landing, param, setting, template, ListingFilter, Post, Preferences, User};
 use askama::Template;
 use hyper::{Body, Request, Response};
 use time::{macros::format_description, OffsetDateTime};
@@ -24,6 +26,9 @@ struct UserTemplate {
 	/// Whether all fetched posts are filtered (to differentiate between no posts fetched in the first place,
 	/// and all fetched posts being filtered).
 	all_posts_filtered: bool,
+	/// Number of fetched posts that were filtered.
+	num_posts_filtered: u64,
+	listing_filter: ListingFilter,
 	/// Whether all posts were hidden because they are NSFW (and user has disabled show NSFW)
 	all_posts_hidden_nsfw: bool,
 	no_posts: bool,
@@ -47,6 +52,10 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sort = param(&path, "sort").unwrap_or_default();
 	let username = req.param("name").unwrap_or_default();
 
+	if blocklist::user_blocked(&username) {
+		return blocklist::unavailable(req).await;
+	}
+
 	// Retrieve info from user about page.
 	let user = user(&username).await.unwrap_or_default();
 
@@ -59,6 +68,8 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	}
 
 	let filters = get_filters(&req);
+	let content_filters = ContentFilter::all(&req);
+	let listing_filter = ListingFilter::new(&req);
 	if filters.contains(&["u_", &username].concat()) {
 		template(UserTemplate {
 			user,
@@ -71,6 +82,8 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 			redirect_url,
 			is_filtered: true,
 			all_posts_filtered: false,
+			num_posts_filtered: 0,
+			listing_filter,
 			all_posts_hidden_nsfw: false,
 			no_posts: false,
 		})
@@ -78,7 +91,7 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 		// Request user posts/comments from Reddit
 		match Post::fetch(&path, false).await {
 			Ok((mut posts, after)) => {
-				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				let (num_posts_filtered, all_posts_filtered) = filter_posts(&mut posts, &filters, &content_filters, &listing_filter);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
 				template(UserTemplate {
@@ -92,6 +105,8 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 					redirect_url,
 					is_filtered: false,
 					all_posts_filtered,
+					num_posts_filtered,
+					listing_filter,
 					all_posts_hidden_nsfw,
 					no_posts,
 				})
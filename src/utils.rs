@@ -1,18 +1,29 @@
-use crate::config::get_setting;
+use crate::config::{get_setting, Config};
 //
 // CRATES
 //
-use crate::{client::json, server::RequestExt};
+use crate::{
+	bookmarks::Bookmark,
+	client::json,
+	content_filter::{self, ContentFilter},
+	policy::Action,
+	server::{Conditional, RequestExt},
+};
 use askama::Template;
 use cookie::Cookie;
-use hyper::{Body, Request, Response};
+use hyper::{
+	header::{HeaderName, HeaderValue},
+	Body, HeaderMap, Request, Response,
+};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rust_embed::RustEmbed;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
 use std::env;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use time::{macros::format_description, Duration, OffsetDateTime};
 use url::Url;
 
@@ -49,6 +60,15 @@ pub struct Flair {
 	pub foreground_color: String,
 }
 
+impl Flair {
+	/// CSS declarations for the flair's colors. As they end up in a `<style>`
+	/// element, characters that could break out of a declaration are removed.
+	pub fn style(&self) -> String {
+		let sanitize = |color: &str| color.chars().filter(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(*c)).collect::<String>();
+		format!("color: {}; background: {};", sanitize(&self.foreground_color), sanitize(&self.background_color))
+	}
+}
+
 // Part of flair, either emoji or text
 #[derive(Clone)]
 pub struct FlairPart {
@@ -288,6 +308,9 @@ pub struct Post {
 	pub title: String,
 	pub community: String,
 	pub body: String,
+	// The body as the plain text it was written in, which the content
+	// policy's body rules match against
+	pub text: String,
 	pub author: Author,
 	pub permalink: String,
 	pub poll: Option<Poll>,
@@ -306,6 +329,8 @@ pub struct Post {
 	pub gallery: Vec<GalleryMedia>,
 	pub awards: Awards,
 	pub nsfw: bool,
+	// Set when the content policy collapses the post
+	pub notice: String,
 }
 
 impl Post {
@@ -342,8 +367,10 @@ impl Post {
 
 			// selftext_html is set for text posts when browsing.
 			let mut body = rewrite_urls(&val(post, "selftext_html"));
+			let mut text = val(post, "selftext");
 			if body.is_empty() {
 				body = rewrite_urls(&val(post, "body_html"));
+				text = val(post, "body");
 			}
 
 			posts.push(Self {
@@ -351,6 +378,7 @@ impl Post {
 				title,
 				community: val(post, "subreddit"),
 				body,
+				text,
 				author: Author {
 					name: val(post, "author"),
 					flair: Flair {
@@ -408,9 +436,13 @@ impl Post {
 				gallery,
 				awards,
 				nsfw: post["data"]["over_18"].as_bool().unwrap_or_default(),
+				notice: String::new(),
 			});
 		}
 
+		// Posts from blocked subreddits and users are left out without a trace
+		posts.retain(|post| !crate::blocklist::post_blocked(post));
+
 		Ok((posts, res["data"]["after"].as_str().unwrap_or_default().to_string()))
 	}
 }
@@ -436,8 +468,10 @@ pub struct Comment {
 	pub awards: Awards,
 	pub collapsed: bool,
 	pub is_filtered: bool,
+	// Set when the content policy collapses the comment
+	pub notice: String,
 	pub more_count: i64,
-	pub prefs: Preferences,
+	pub prefs: Arc<Preferences>,
 }
 
 #[derive(Default, Clone)]
@@ -560,7 +594,7 @@ pub struct Params {
 	pub before: Option<String>,
 }
 
-#[derive(Default)]
+#[derive(Default, Hash)]
 pub struct Preferences {
 	pub available_themes: Vec<String>,
 	pub theme: String,
@@ -578,6 +612,137 @@ pub struct Preferences {
 	pub subscriptions: Vec<String>,
 	pub filters: Vec<String>,
 	pub hide_awards: String,
+	pub feeds: Vec<Feed>,
+	pub content_filters: Vec<ContentFilter>,
+	pub min_score: String,
+	pub min_ratio: String,
+	pub post_types: String,
+	pub hide_stickied: String,
+	pub track_read: String,
+	pub hidden_posts: Vec<String>,
+	pub read_posts: Vec<String>,
+	pub bookmarks: Vec<Bookmark>,
+	pub sub_preferences: Vec<SubPreferences>,
+}
+
+/// A custom feed: a named multireddit with its own default sort and filters,
+/// browsable at `/f/{name}`.
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
+pub struct Feed {
+	pub name: String,
+	pub subs: Vec<String>,
+	pub sort: String,
+	pub filters: Vec<String>,
+}
+
+/// Separates the feeds in the `feeds` cookie.
+pub const FEED_SEPARATOR: char = '|';
+
+impl Feed {
+	/// Parses a feed from the form it is kept in within the `feeds` cookie,
+	/// `name:sub+sub:sort:filter+filter`.
+	pub fn parse(feed: &str) -> Option<Self> {
+		let mut parts = feed.split(':');
+		let list = |part: Option<&str>| -> Vec<String> { part.unwrap_or_default().split('+').filter(|s| !s.is_empty()).map(String::from).collect() };
+
+		let feed = Self {
+			name: parts.next()?.to_string(),
+			subs: list(parts.next()),
+			sort: parts.next().unwrap_or_default().to_string(),
+			filters: list(parts.next()),
+		};
+
+		let is_sub = |sub: &String| is_sub_name(sub);
+		let valid = Self::is_name(&feed.name)
+			&& !feed.subs.is_empty()
+			&& feed.subs.iter().all(is_sub)
+			&& feed.filters.iter().all(is_sub)
+			&& ["", "hot", "new", "top", "rising", "controversial"].contains(&feed.sort.as_str())
+			&& parts.next().is_none();
+		valid.then_some(feed)
+	}
+
+	/// Whether `name` can be used as the name of a feed.
+	pub fn is_name(name: &str) -> bool {
+		!name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+	}
+
+	/// Whether `feed` is a valid feed in its cookie form.
+	pub fn is_valid(feed: &str) -> bool {
+		Self::parse(feed).is_some()
+	}
+
+	/// Reads the feeds from the `feeds` cookie.
+	pub fn all(req: &Request<Body>) -> Vec<Self> {
+		setting(req, "feeds").split(FEED_SEPARATOR).filter_map(Self::parse).collect()
+	}
+}
+
+impl std::fmt::Display for Feed {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}:{}:{}:{}", self.name, self.subs.join("+"), self.sort, self.filters.join("+"))
+	}
+}
+
+/// Post sort, comment sort and layout for one subreddit, overriding the
+/// global preferences. Empty values fall back to those.
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
+pub struct SubPreferences {
+	pub sub: String,
+	pub post_sort: String,
+	pub comment_sort: String,
+	pub layout: String,
+}
+
+/// Separates the subreddits in the `sub_preferences` cookie.
+pub const SUB_PREFERENCES_SEPARATOR: char = '|';
+
+impl SubPreferences {
+	/// Parses the preferences from the form they are kept in within the
+	/// `sub_preferences` cookie, `sub:post_sort:comment_sort:layout`.
+	pub fn parse(prefs: &str) -> Option<Self> {
+		let mut parts = prefs.split(':');
+		let prefs = Self {
+			sub: parts.next()?.to_string(),
+			post_sort: parts.next()?.to_string(),
+			comment_sort: parts.next()?.to_string(),
+			layout: parts.next()?.to_string(),
+		};
+		let valid = !prefs.sub.is_empty()
+			&& prefs.sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+			&& ["", "hot", "new", "top", "rising", "controversial"].contains(&prefs.post_sort.as_str())
+			&& ["", "confidence", "top", "new", "controversial", "old"].contains(&prefs.comment_sort.as_str())
+			&& ["", "card", "clean", "compact"].contains(&prefs.layout.as_str())
+			&& !prefs.is_empty()
+			&& parts.next().is_none();
+		valid.then_some(prefs)
+	}
+
+	/// Whether `prefs` are valid subreddit preferences in their cookie form.
+	pub fn is_valid(prefs: &str) -> bool {
+		Self::parse(prefs).is_some()
+	}
+
+	/// Whether none of the global preferences is overridden.
+	pub fn is_empty(&self) -> bool {
+		self.post_sort.is_empty() && self.comment_sort.is_empty() && self.layout.is_empty()
+	}
+
+	/// Reads the preferences of every subreddit from the `sub_preferences` cookie.
+	pub fn all(req: &Request<Body>) -> Vec<Self> {
+		setting(req, "sub_preferences").split(SUB_PREFERENCES_SEPARATOR).filter_map(Self::parse).collect()
+	}
+
+	/// Reads the preferences of `sub`, which are empty if none are set.
+	pub fn get(req: &Request<Body>, sub: &str) -> Self {
+		Self::all(req).into_iter().find(|prefs| prefs.sub.eq_ignore_ascii_case(sub)).unwrap_or_default()
+	}
+}
+
+impl std::fmt::Display for SubPreferences {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}:{}:{}:{}", self.sub, self.post_sort, self.comment_sort, self.layout)
+	}
 }
 
 #[derive(RustEmbed)]
@@ -586,7 +751,8 @@ pub struct Preferences {
 pub struct ThemeAssets;
 
 impl Preferences {
-	// Build preferences from cookies
+	// Build preferences from cookies, or from the server-side profile the
+	// browser is linked to
 	pub fn new(req: &Request<Body>) -> Self {
 		// Read available theme names from embedded css files.
 		// Always make the default "system" theme available.
@@ -612,8 +778,154 @@ impl Preferences {
 			subscriptions: setting(req, "subscriptions").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
 			filters: setting(req, "filters").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
 			hide_awards: setting(req, "hide_awards"),
+			feeds: Feed::all(req),
+			content_filters: ContentFilter::all(req),
+			min_score: setting(req, "min_score"),
+			min_ratio: setting(req, "min_ratio"),
+			post_types: setting(req, "post_types"),
+			hide_stickied: setting(req, "hide_stickied"),
+			track_read: setting(req, "track_read"),
+			hidden_posts: post_ids(req, "hidden_posts", MAX_HIDDEN_POSTS),
+			read_posts: post_ids(req, "read_posts", MAX_READ_POSTS),
+			bookmarks: Bookmark::all(req),
+			sub_preferences: SubPreferences::all(req),
+		}
+	}
+
+	/// Preferences for pages of `sub`, with its layout in place of the global one.
+	pub fn for_sub(req: &Request<Body>, sub: &str) -> Self {
+		let mut prefs = Self::new(req);
+		if let Some(sub_prefs) = prefs.sub_preferences.iter().find(|prefs| prefs.sub.eq_ignore_ascii_case(sub) && !prefs.layout.is_empty()) {
+			prefs.layout = sub_prefs.layout.clone();
+		}
+		prefs
+	}
+
+	/// Whether the post with this ID, or the comment on it, is saved.
+	pub fn is_saved(&self, id: &str, comment: &str) -> bool {
+		self.bookmarks.iter().any(|bookmark| bookmark.id == id && bookmark.comment == comment)
+	}
+}
+
+/// Score, upvote ratio, post type and stickied post filters for listings.
+/// They are set as preferences, and can be overridden for a page through the
+/// query parameters of the same names, e.g. `?min_score=100&post_types=media`.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct ListingFilter {
+	pub min_score: Option<i64>,
+	/// Minimum upvote ratio, in percent.
+	pub min_ratio: Option<i64>,
+	/// `media` or `text`, or empty for every type.
+	pub post_types: String,
+	pub hide_stickied: bool,
+	/// The query parameters overriding preferences, to keep on the other pages
+	/// of the listing.
+	pub query: String,
+	/// The query of the current page with every filter turned off.
+	pub show_all: String,
+	/// Posts the user has hidden, which are left out of listings whatever
+	/// the other filters are.
+	pub hidden: HashSet<String>,
+}
+
+impl ListingFilter {
+	pub const PARAMS: [&'static str; 4] = ["min_score", "min_ratio", "post_types", "hide_stickied"];
+
+	pub fn new(req: &Request<Body>) -> Self {
+		let query: Vec<(String, String)> = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).into_owned().collect();
+		let overridden = |name: &str| query.iter().rev().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+		let value = |name: &str| overridden(name).unwrap_or_else(|| setting(req, name));
+		let number = |name: &str| value(name).trim().parse::<i64>().ok();
+
+		let encode = |pairs: Vec<(&str, String)>| url::form_urlencoded::Serializer::new(String::new()).extend_pairs(pairs).finish();
+		let others = query
+			.iter()
+			.filter(|(key, _)| !Self::PARAMS.contains(&key.as_str()))
+			.map(|(key, value)| (key.as_str(), value.clone()));
+		let off = [("min_score", ""), ("min_ratio", ""), ("post_types", "all"), ("hide_stickied", "off")].map(|(key, value)| (key, value.to_string()));
+
+		Self {
+			min_score: number("min_score"),
+			min_ratio: number("min_ratio").filter(|ratio| *ratio > 0),
+			post_types: match value("post_types").as_str() {
+				types @ ("media" | "text") => types.to_string(),
+				_ => String::new(),
+			},
+			hide_stickied: value("hide_stickied") == "on",
+			query: encode(Self::PARAMS.iter().filter_map(|&name| Some((name, overridden(name)?))).collect()),
+			show_all: encode(others.chain(off).collect()),
+			hidden: post_ids(req, "hidden_posts", MAX_HIDDEN_POSTS).into_iter().collect(),
 		}
 	}
+
+	/// Whether any of the filters is set.
+	pub fn is_active(&self) -> bool {
+		self.min_score.is_some() || self.min_ratio.is_some() || !self.post_types.is_empty() || self.hide_stickied
+	}
+
+	/// Whether a post passes the filters. Comments, as listed on user
+	/// profiles, are only filtered by score.
+	pub fn matches(&self, post: &Post) -> bool {
+		if self.hidden.contains(&post.id) {
+			return false;
+		}
+		let score = post.score.1.parse::<i64>().ok();
+		if self.min_score.is_some_and(|min| score.is_some_and(|score| score < min)) {
+			return false;
+		}
+		if post.title.is_empty() {
+			return true;
+		}
+
+		let media = matches!(post.post_type.as_str(), "image" | "video" | "gif" | "gallery");
+		let type_matches = match self.post_types.as_str() {
+			"media" => media,
+			"text" => post.post_type == "self",
+			_ => true,
+		};
+		type_matches && self.min_ratio.is_none_or(|min| post.upvote_ratio >= min) && !(self.hide_stickied && post.flags.stickied)
+	}
+}
+
+/// Posts hidden from listings are kept in the `hidden_posts` setting, up to
+/// this many; the oldest are dropped first.
+pub const MAX_HIDDEN_POSTS: usize = 500;
+
+/// Posts marked as read are kept in the `read_posts` setting, up to this many.
+pub const MAX_READ_POSTS: usize = 300;
+
+/// Settings kept in cookies even when the browser is linked to a server-side
+/// profile. Read posts change on every post opened, which is too often to
+/// rewrite the profile for.
+pub const COOKIE_ONLY_SETTINGS: [&str; 1] = ["read_posts"];
+
+/// Whether `name` can be listed in subscriptions, filters and feeds: a
+/// subreddit name, or a user as `u_{name}`. Usernames may contain `-`.
+pub fn is_sub_name(name: &str) -> bool {
+	!name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Whether `id` has the form of a Reddit post ID.
+pub fn is_post_id(id: &str) -> bool {
+	!id.is_empty() && id.len() <= 12 && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+/// Reads a `+`-separated list of post IDs, like `hidden_posts`, from the
+/// request's settings, keeping only the latest `max`.
+pub fn post_ids(req: &Request<Body>, name: &str, max: usize) -> Vec<String> {
+	let mut ids: Vec<String> = setting(req, name).split('+').filter(|id| is_post_id(id)).map(String::from).collect();
+	let excess = ids.len().saturating_sub(max);
+	ids.drain(..excess);
+	ids
+}
+
+/// Adds a post ID to the end of a list of post IDs, moving it there if it
+/// was already listed and dropping the oldest ones past `max`.
+pub fn push_post_id(ids: &mut Vec<String>, id: &str, max: usize) {
+	ids.retain(|listed| listed != id);
+	ids.push(id.to_string());
+	let excess = ids.len().saturating_sub(max);
+	ids.drain(..excess);
 }
 
 /// Gets a `HashSet` of filters from the cookie in the given `Request`.
@@ -625,9 +937,18 @@ pub fn get_filters(req: &Request<Body>) -> HashSet<String> {
 /// a subreddit name or a user name). If a `Post`'s subreddit or author is
 /// found in the filters, it is removed.
 ///
+/// Posts matching one of the `content_filters` are removed as well, or
+/// collapsed behind a notice, depending on the filter.
+/// Posts that do not pass the `listing_filter` are removed too.
+///
 /// The first value of the return tuple is the number of posts filtered. The
 /// second return value is `true` if all posts were filtered.
-pub fn filter_posts(posts: &mut Vec<Post>, filters: &HashSet<String>) -> (u64, bool) {
+///
+/// The instance's content policy is applied as well; posts it hides are not
+/// counted.
+pub fn filter_posts(posts: &mut Vec<Post>, filters: &HashSet<String>, content_filters: &[ContentFilter], listing_filter: &ListingFilter) -> (u64, bool) {
+	crate::policy::apply(posts);
+
 	// This is the length of the Vec<Post> prior to applying the filter.
 	let lb: u64 = posts.len().try_into().unwrap_or(0);
 
@@ -635,6 +956,18 @@ pub fn filter_posts(posts: &mut Vec<Post>, filters: &HashSet<String>) -> (u64, b
 		(0, false)
 	} else {
 		posts.retain(|p| !(filters.contains(&p.community) || filters.contains(&["u_", &p.author.name].concat())));
+		posts.retain_mut(|p| match content_filter::check_post(content_filters, p) {
+			Some((Action::Hide, _)) => false,
+			Some((Action::Collapse, notice)) => {
+				// The instance's notice takes precedence
+				if p.notice.is_empty() {
+					p.notice = notice;
+				}
+				true
+			}
+			None => true,
+		});
+		posts.retain(|p| listing_filter.matches(p));
 
 		// Get the length of the Vec<Post> after applying the filter.
 		// If lb > la, then at least one post was removed.
@@ -677,6 +1010,7 @@ pub async fn parse_post(post: &serde_json::Value) -> Post {
 		title: val(post, "title"),
 		community: val(post, "subreddit"),
 		body,
+		text: val(post, "selftext"),
 		author: Author {
 			name: val(post, "author"),
 			flair: Flair {
@@ -730,6 +1064,7 @@ pub async fn parse_post(post: &serde_json::Value) -> Post {
 		gallery,
 		awards,
 		nsfw: post["data"]["over_18"].as_bool().unwrap_or_default(),
+		notice: String::new(),
 	}
 }
 
@@ -752,6 +1087,16 @@ pub fn param(path: &str, value: &str) -> Option<String> {
 
 // Retrieve the value of a setting by name
 pub fn setting(req: &Request<Body>, name: &str) -> String {
+	// Browsers linked to a server-side profile read their settings from it
+	// rather than from cookies
+	if let Some(profile) = crate::profile::get(req).filter(|_| !COOKIE_ONLY_SETTINGS.contains(&name)) {
+		return profile
+			.get(name)
+			.cloned()
+			.or_else(|| crate::config::get_setting(&format!("LIBREDDIT_DEFAULT_{}", name.to_uppercase())))
+			.unwrap_or_default();
+	}
+
 	// Parse a cookie value from request
 	req
 		.cookie(name)
@@ -936,13 +1281,38 @@ pub fn val(j: &Value, k: &str) -> String {
 //
 
 pub fn template(t: impl Template) -> Result<Response<Body>, String> {
-	Ok(
-		Response::builder()
-			.status(200)
-			.header("content-type", "text/html")
-			.body(t.render().unwrap_or_default().into())
-			.unwrap_or_default(),
-	)
+	let mut res = Response::builder()
+		.status(200)
+		.header("content-type", "text/html")
+		.body(t.render().unwrap_or_default().into())
+		.unwrap_or_default();
+
+	// Rendered pages only change when the upstream data or the user's
+	// preferences do, so let the server answer conditional requests for them.
+	res.extensions_mut().insert(Conditional);
+
+	Ok(res)
+}
+
+/// Digests everything besides upstream data that goes into a rendered page:
+/// the effective `Preferences` of the request, the instance configuration and
+/// the build of Libreddit doing the rendering. The server combines this with
+/// the upstream payloads to derive the page's ETag.
+pub fn etag_seed(req: &Request<Body>) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	Preferences::new(req).hash(&mut hasher);
+	format!("{:?}", crate::config::current()).hash(&mut hasher);
+	env!("CARGO_PKG_VERSION").hash(&mut hasher);
+	env!("GIT_HASH").hash(&mut hasher);
+	hasher.finish()
+}
+
+/// Returns `path` if it is a page of this instance, or `/` otherwise, so that
+/// redirects taken from forms can't send users to other sites.
+pub fn local_path(path: Option<String>) -> String {
+	path
+		.filter(|path| path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\"))
+		.unwrap_or_else(|| "/".to_string())
 }
 
 pub fn redirect(path: String) -> Response<Body> {
@@ -956,6 +1326,11 @@ pub fn redirect(path: String) -> Response<Body> {
 
 /// Renders a generic error landing page.
 pub async fn error(req: Request<Body>, msg: impl ToString) -> Result<Response<Body>, String> {
+	error_with_status(req, 404, msg).await
+}
+
+/// Renders the themed error page with the given HTTP status code.
+pub async fn error_with_status(req: Request<Body>, status: u16, msg: impl ToString) -> Result<Response<Body>, String> {
 	let url = req.uri().to_string();
 	let body = ErrorTemplate {
 		msg: msg.to_string(),
@@ -965,7 +1340,101 @@ pub async fn error(req: Request<Body>, msg: impl ToString) -> Result<Response<Bo
 	.render()
 	.unwrap_or_default();
 
-	Ok(Response::builder().status(404).header("content-type", "text/html").body(body.into()).unwrap_or_default())
+	Ok(Response::builder().status(status).header("content-type", "text/html").body(body.into()).unwrap_or_default())
+}
+
+/// Returns the path prefix the instance is served under (set through
+/// `LIBREDDIT_BASE_PATH`), with a leading but no trailing slash, e.g.
+/// `/reddit`. Empty if the instance is served at the root.
+pub fn base_path() -> String {
+	match crate::config::get_setting("LIBREDDIT_BASE_PATH") {
+		Some(path) if !path.trim_matches('/').is_empty() => format!("/{}", path.trim_matches('/')),
+		_ => String::new(),
+	}
+}
+
+// The security headers, along with the configuration they were built from
+static SECURITY_HEADERS: Mutex<Option<(Arc<Config>, HeaderMap)>> = Mutex::new(None);
+
+/// Returns the security headers for the current configuration. They are only
+/// built again once the configuration is reloaded.
+pub fn security_headers() -> HeaderMap {
+	let config = crate::config::current();
+
+	let Ok(mut cached) = SECURITY_HEADERS.lock() else {
+		return build_security_headers();
+	};
+
+	match &*cached {
+		Some((built_from, headers)) if Arc::ptr_eq(built_from, &config) => headers.clone(),
+		_ => {
+			let headers = build_security_headers();
+			*cached = Some((config, headers.clone()));
+			headers
+		}
+	}
+}
+
+/// Builds the security headers sent with every response from
+/// `LIBREDDIT_CONTENT_SECURITY_POLICY`, `LIBREDDIT_X_FRAME_OPTIONS`,
+/// `LIBREDDIT_REFERRER_POLICY`, `LIBREDDIT_STRICT_TRANSPORT_SECURITY` and
+/// `LIBREDDIT_EXTRA_HEADERS`. Setting one of them to an empty value removes
+/// the header.
+fn build_security_headers() -> HeaderMap {
+	use crate::config::{default_strict_transport_security, DEFAULT_CONTENT_SECURITY_POLICY, DEFAULT_REFERRER_POLICY, DEFAULT_X_FRAME_OPTIONS};
+
+	let mut headers = HeaderMap::new();
+	headers.insert("X-Content-Type-Options", HeaderValue::from_static("nosniff"));
+
+	let hsts = default_strict_transport_security();
+	for (name, setting, default) in [
+		("Content-Security-Policy", "LIBREDDIT_CONTENT_SECURITY_POLICY", DEFAULT_CONTENT_SECURITY_POLICY),
+		("X-Frame-Options", "LIBREDDIT_X_FRAME_OPTIONS", DEFAULT_X_FRAME_OPTIONS),
+		("Referrer-Policy", "LIBREDDIT_REFERRER_POLICY", DEFAULT_REFERRER_POLICY),
+		("Strict-Transport-Security", "LIBREDDIT_STRICT_TRANSPORT_SECURITY", hsts.as_str()),
+	] {
+		let value = get_setting(setting).unwrap_or_else(|| default.to_string());
+		set_header(&mut headers, name, value.trim());
+	}
+
+	for (name, value) in parse_extra_headers(&get_setting("LIBREDDIT_EXTRA_HEADERS").unwrap_or_default()) {
+		set_header(&mut headers, name, value);
+	}
+
+	headers
+}
+
+/// Sets a header, or removes it if `value` is empty.
+fn set_header(headers: &mut HeaderMap, name: &str, value: &str) {
+	let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+		eprintln!("Ignoring invalid header name {:?}", name);
+		return;
+	};
+
+	if value.is_empty() {
+		headers.remove(name);
+	} else if let Ok(value) = HeaderValue::from_str(value) {
+		headers.insert(name, value);
+	} else {
+		eprintln!("Ignoring invalid value for header {}", name);
+	}
+}
+
+/// Splits `LIBREDDIT_EXTRA_HEADERS` into header names and values. Headers are
+/// given as `Name: value`, separated by `|` or newlines.
+fn parse_extra_headers(headers: &str) -> Vec<(&str, &str)> {
+	headers
+		.split(['|', '\n'])
+		.map(str::trim)
+		.filter(|header| !header.is_empty())
+		.filter_map(|header| match header.split_once(':') {
+			Some((name, value)) => Some((name.trim(), value.trim())),
+			None => {
+				eprintln!("Ignoring extra header without a value: {:?}", header);
+				None
+			}
+		})
+		.collect()
 }
 
 /// Returns true if the config/env variable `LIBREDDIT_SFW_ONLY` carries the
@@ -1025,7 +1494,7 @@ pub async fn nsfw_landing(req: Request<Body>, req_url: String) -> Result<Respons
 
 #[cfg(test)]
 mod tests {
-	use super::{format_num, format_url, rewrite_urls};
+	use super::{format_num, format_url, parse_extra_headers, post_ids, push_post_id, rewrite_urls, Feed, Flair, ListingFilter, SubPreferences};
 
 	#[test]
 	fn format_num_works() {
@@ -1074,4 +1543,91 @@ mod tests {
 		assert_eq!(format_url("nsfw"), "");
 		assert_eq!(format_url("spoiler"), "");
 	}
+
+	#[test]
+	fn test_parse_extra_headers() {
+		assert_eq!(
+			parse_extra_headers("Permissions-Policy: interest-cohort=() | X-Frame-Options:\nX-Robots-Tag: noindex, nofollow"),
+			vec![("Permissions-Policy", "interest-cohort=()"), ("X-Frame-Options", ""), ("X-Robots-Tag", "noindex, nofollow")]
+		);
+		assert!(parse_extra_headers("").is_empty());
+		assert!(parse_extra_headers("no-colon").is_empty());
+	}
+
+	#[test]
+	fn test_flair_style() {
+		let flair = Flair {
+			flair_parts: Vec::new(),
+			text: String::new(),
+			background_color: "red;}body{display:none".to_string(),
+			foreground_color: "#ffffff".to_string(),
+		};
+		assert_eq!(flair.style(), "color: #ffffff; background: redbodydisplaynone;");
+	}
+
+	#[test]
+	fn test_listing_filter() {
+		let req = hyper::Request::builder()
+			.uri("/r/rust?sort=top&min_score=100&post_types=text&min_ratio=0")
+			.body(hyper::Body::empty())
+			.unwrap();
+		let filter = ListingFilter::new(&req);
+		assert_eq!(filter.min_score, Some(100));
+		assert_eq!(filter.min_ratio, None);
+		assert_eq!(filter.post_types, "text");
+		assert!(!filter.hide_stickied);
+		assert_eq!(filter.query, "min_score=100&min_ratio=0&post_types=text");
+		assert_eq!(filter.show_all, "sort=top&min_score=&min_ratio=&post_types=all&hide_stickied=off");
+
+		let req = hyper::Request::builder().uri("/r/rust?post_types=all").body(hyper::Body::empty()).unwrap();
+		assert!(!ListingFilter::new(&req).is_active());
+	}
+
+	#[test]
+	fn test_push_post_id() {
+		let mut ids = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+		push_post_id(&mut ids, "a", 3);
+		assert_eq!(ids, ["b", "c", "a"]);
+		push_post_id(&mut ids, "d", 3);
+		assert_eq!(ids, ["c", "a", "d"]);
+	}
+
+	#[test]
+	fn test_post_ids() {
+		let req = hyper::Request::builder()
+			.header("Cookie", "read_posts=a1+b2+../c+c3+d4")
+			.body(hyper::Body::empty())
+			.unwrap();
+		assert_eq!(post_ids(&req, "read_posts", 10), ["a1", "b2", "c3", "d4"]);
+		assert_eq!(post_ids(&req, "read_posts", 2), ["c3", "d4"]);
+	}
+
+	#[test]
+	fn test_feed() {
+		let feed = Feed::parse("news:worldnews+europe:new:u_spez").unwrap();
+		assert_eq!(feed.subs, vec!["worldnews", "europe"]);
+		assert_eq!(feed.sort, "new");
+		assert_eq!(feed.filters, vec!["u_spez"]);
+		assert_eq!(feed.to_string(), "news:worldnews+europe:new:u_spez");
+
+		assert!(Feed::is_valid("work:rust::"));
+		assert!(!Feed::is_valid("work:::"));
+		assert!(!Feed::is_valid("work:rust:best:"));
+		assert!(!Feed::is_valid("my work:rust::"));
+		assert!(!Feed::is_valid("work:r/rust::"));
+	}
+
+	#[test]
+	fn test_sub_preferences() {
+		let prefs = SubPreferences::parse("AskHistorians:top:old:compact").unwrap();
+		assert_eq!(prefs.post_sort, "top");
+		assert_eq!(prefs.to_string(), "AskHistorians:top:old:compact");
+
+		assert!(SubPreferences::is_valid("rust:::card"));
+		assert!(!SubPreferences::is_valid("rust:::"));
+		assert!(!SubPreferences::is_valid("rust:best::"));
+		assert!(!SubPreferences::is_valid("rust:::gallery"));
+		assert!(!SubPreferences::is_valid("rust:new::card:"));
+		assert!(!SubPreferences::is_valid("r/rust:new::"));
+	}
 }